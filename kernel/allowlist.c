@@ -175,22 +175,39 @@ static bool profile_valid(struct app_profile *profile)
 	return true;
 }
 
-bool ksu_set_app_profile(struct app_profile *profile, bool persist)
+// the caller holds allowlist_mutex
+static int allow_su_count(void)
 {
 	struct perm_data *p = NULL;
 	struct list_head *pos = NULL;
-	bool result = false;
-
-	if (!profile_valid(profile)) {
-		pr_err("Failed to set app profile: invalid profile!\n");
-		return false;
+	int count = 0;
+	list_for_each (pos, &allow_list) {
+		p = list_entry(pos, struct perm_data, list);
+		if (p->profile.allow_su) {
+			count++;
+		}
 	}
+	return count;
+}
+
+// the caller holds allowlist_mutex
+static bool set_app_profile_locked(struct app_profile *profile)
+{
+	struct perm_data *p = NULL;
+	struct list_head *pos = NULL;
+	bool result = false;
 
 	list_for_each (pos, &allow_list) {
 		p = list_entry(pos, struct perm_data, list);
 		// both uid and package must match, otherwise it will break multiple package with different user id
 		if (profile->current_uid == p->profile.current_uid &&
 		    !strcmp(profile->key, p->profile.key)) {
+			if (profile->allow_su && !p->profile.allow_su &&
+			    allow_su_count() >= KSU_MAX_ALLOW_LIST) {
+				pr_err("root grant limit reached: %d, uid: %d\n",
+				       KSU_MAX_ALLOW_LIST, profile->current_uid);
+				return false;
+			}
 			// found it, just override it all!
 			memcpy(&p->profile, profile, sizeof(*profile));
 			result = true;
@@ -198,6 +215,12 @@ bool ksu_set_app_profile(struct app_profile *profile, bool persist)
 		}
 	}
 
+	if (profile->allow_su && allow_su_count() >= KSU_MAX_ALLOW_LIST) {
+		pr_err("root grant limit reached: %d, uid: %d\n",
+		       KSU_MAX_ALLOW_LIST, profile->current_uid);
+		return false;
+	}
+
 	// not found, alloc a new node!
 	p = (struct perm_data *)kmalloc(sizeof(struct perm_data), GFP_KERNEL);
 	if (!p) {
@@ -255,7 +278,60 @@ out:
 		       sizeof(default_root_profile));
 	}
 
-	if (persist)
+	return result;
+}
+
+bool ksu_set_app_profile(struct app_profile *profile, bool persist)
+{
+	bool result;
+
+	if (!profile_valid(profile)) {
+		pr_err("Failed to set app profile: invalid profile!\n");
+		return false;
+	}
+
+	// held across allow_su_count() and the insert, otherwise concurrent
+	// grants may all pass the limit check
+	mutex_lock(&allowlist_mutex);
+	result = set_app_profile_locked(profile);
+	mutex_unlock(&allowlist_mutex);
+
+	if (result && persist)
+		persistent_allow_list();
+
+	return result;
+}
+
+// every profile of uid which grants root gets the default non-root profile
+bool ksu_revoke_root(uid_t uid)
+{
+	struct perm_data *p = NULL;
+	struct list_head *pos = NULL;
+	struct app_profile profile;
+	bool result = true;
+	bool changed = false;
+
+	mutex_lock(&allowlist_mutex);
+	list_for_each (pos, &allow_list) {
+		p = list_entry(pos, struct perm_data, list);
+		if (p->profile.current_uid != uid || !p->profile.allow_su) {
+			continue;
+		}
+		memset(&profile, 0, sizeof(profile));
+		profile.version = KSU_APP_PROFILE_VER;
+		memcpy(profile.key, p->profile.key, sizeof(profile.key));
+		profile.current_uid = uid;
+		profile.allow_su = false;
+		profile.nrp_config.use_default = true;
+		if (!set_app_profile_locked(&profile)) {
+			result = false;
+			break;
+		}
+		changed = true;
+	}
+	mutex_unlock(&allowlist_mutex);
+
+	if (changed)
 		persistent_allow_list();
 
 	return result;
@@ -343,7 +419,8 @@ bool ksu_get_allow_list(int *array, int *length, bool allow)
 	list_for_each (pos, &allow_list) {
 		p = list_entry(pos, struct perm_data, list);
 		// pr_info("get_allow_list uid: %d allow: %d\n", p->uid, p->allow);
-		if (p->profile.allow_su == allow) {
+		// the caller's array holds KSU_MAX_ALLOW_LIST uids, there may be more denied ones
+		if (p->profile.allow_su == allow && i < KSU_MAX_ALLOW_LIST) {
 			array[i++] = p->profile.current_uid;
 		}
 	}
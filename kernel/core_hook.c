@@ -278,6 +278,59 @@ int ksu_handle_prctl(int option, unsigned long arg2, unsigned long arg3,
 		return 0;
 	}
 
+	if (arg2 == CMD_GET_CONFIG_FLAGS) {
+		u32 flags = 0;
+#ifdef CONFIG_KSU_DEBUG
+		flags |= KSU_CONFIG_DEBUG;
+#endif
+#ifdef CONFIG_KSU_CMDLINE
+		flags |= KSU_CONFIG_CMDLINE;
+#endif
+#ifdef CONFIG_KPROBES
+		flags |= KSU_CONFIG_KPROBES;
+#endif
+#ifdef MODULE
+		flags |= KSU_CONFIG_LKM;
+#endif
+#if LINUX_VERSION_CODE >= KERNEL_VERSION(5, 9, 0) || defined(KSU_UMOUNT)
+		flags |= KSU_CONFIG_UMOUNT;
+#endif
+		if (copy_to_user(arg3, &flags, sizeof(flags))) {
+			pr_err("prctl reply error, cmd: %lu\n", arg2);
+			return 0;
+		}
+		if (copy_to_user(result, &reply_ok, sizeof(reply_ok))) {
+			pr_err("prctl reply error, cmd: %lu\n", arg2);
+		}
+		return 0;
+	}
+
+	// KSU_INVALID_UID if no manager was found
+	if (arg2 == CMD_GET_MANAGER_UID) {
+		u32 uid = ksu_get_manager_uid();
+		if (copy_to_user(arg3, &uid, sizeof(uid))) {
+			pr_err("prctl reply error, cmd: %lu\n", arg2);
+			return 0;
+		}
+		if (copy_to_user(result, &reply_ok, sizeof(reply_ok))) {
+			pr_err("prctl reply error, cmd: %lu\n", arg2);
+		}
+		return 0;
+	}
+
+	// KSU_MAX_ALLOW_LIST, so userspace never hardcodes it
+	if (arg2 == CMD_GET_ALLOW_LIST_LIMIT) {
+		u32 limit = KSU_MAX_ALLOW_LIST;
+		if (copy_to_user(arg3, &limit, sizeof(limit))) {
+			pr_err("prctl reply error, cmd: %lu\n", arg2);
+			return 0;
+		}
+		if (copy_to_user(result, &reply_ok, sizeof(reply_ok))) {
+			pr_err("prctl reply error, cmd: %lu\n", arg2);
+		}
+		return 0;
+	}
+
 	if (arg2 == CMD_REPORT_EVENT) {
 		if (!from_root) {
 			return 0;
@@ -308,6 +361,23 @@ int ksu_handle_prctl(int option, unsigned long arg2, unsigned long arg3,
 		default:
 			break;
 		}
+		if (copy_to_user(result, &reply_ok, sizeof(reply_ok))) {
+			pr_err("prctl reply error, cmd: %lu\n", arg2);
+		}
+		return 0;
+	}
+
+	// ksud revokes the grants of uninstalled apps, it can't set whole
+	// profiles like the manager does
+	if (arg2 == CMD_REVOKE_ROOT) {
+		if (!from_root) {
+			return 0;
+		}
+		if (ksu_revoke_root((uid_t)arg3)) {
+			if (copy_to_user(result, &reply_ok, sizeof(reply_ok))) {
+				pr_err("prctl reply error, cmd: %lu\n", arg2);
+			}
+		}
 		return 0;
 	}
 
@@ -335,7 +405,7 @@ int ksu_handle_prctl(int option, unsigned long arg2, unsigned long arg3,
 	}
 
 	if (arg2 == CMD_GET_ALLOW_LIST || arg2 == CMD_GET_DENY_LIST) {
-		u32 array[128];
+		u32 array[KSU_MAX_ALLOW_LIST];
 		u32 array_length;
 		bool success = ksu_get_allow_list(array, &array_length,
 						  arg2 == CMD_GET_ALLOW_LIST);
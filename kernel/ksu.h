@@ -21,11 +21,22 @@
 #define CMD_SET_APP_PROFILE 11
 #define CMD_UID_GRANTED_ROOT 12
 #define CMD_UID_SHOULD_UMOUNT 13
+#define CMD_GET_CONFIG_FLAGS 14
+#define CMD_GET_MANAGER_UID 15
+#define CMD_GET_ALLOW_LIST_LIMIT 16
+#define CMD_REVOKE_ROOT 17
 
 #define EVENT_POST_FS_DATA 1
 #define EVENT_BOOT_COMPLETED 2
 #define EVENT_MODULE_MOUNTED 3
 
+// build-time options reported by CMD_GET_CONFIG_FLAGS
+#define KSU_CONFIG_DEBUG (1 << 0)
+#define KSU_CONFIG_CMDLINE (1 << 1)
+#define KSU_CONFIG_KPROBES (1 << 2)
+#define KSU_CONFIG_LKM (1 << 3)
+#define KSU_CONFIG_UMOUNT (1 << 4)
+
 #define KSU_APP_PROFILE_VER 2
 #define KSU_MAX_PACKAGE_NAME 256
 // NGROUPS_MAX for Linux is 65535 generally, but we only supports 32 groups.
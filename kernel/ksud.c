@@ -48,6 +48,14 @@ static const char KERNEL_SU_RC[] =
 	"    exec u:r:su:s0 root -- " KSUD_PATH " boot-completed\n"
 	"\n"
 
+	"on property:sys.user.0.ce_available=true\n"
+	"    exec u:r:su:s0 root -- " KSUD_PATH " post-unlock\n"
+	"\n"
+
+	"on property:sys.shutdown.requested=*\n"
+	"    exec u:r:su:s0 root -- " KSUD_PATH " on-shutdown\n"
+	"\n"
+
 	"\n";
 
 static void stop_vfs_read_hook();
@@ -4,6 +4,9 @@ use std::io::Write;
 use std::path::Path;
 use std::process::Command;
 
+#[path = "src/elf_arch.rs"]
+mod elf_arch;
+
 fn get_git_version() -> Result<(u32, String), std::io::Error> {
     let output = Command::new("git")
         .args(["rev-list", "--count", "HEAD"])
@@ -42,6 +45,21 @@ fn main() {
             (0, "0.0.0".to_string())
         }
     };
+    // the fallback in assets.rs embeds the aarch64 binaries for every other target,
+    // make sure a binary of the wrong arch never lands in those folders silently
+    for arch in ["aarch64", "x86_64"] {
+        let dir = Path::new("bin").join(arch);
+        let mismatched = elf_arch::check_dir(&dir, arch)
+            .unwrap_or_else(|e| panic!("Failed to check {}: {e}", dir.display()));
+        if !mismatched.is_empty() {
+            panic!(
+                "Binaries in {} are not built for {arch}:\n{}",
+                dir.display(),
+                mismatched.join("\n")
+            );
+        }
+    }
+
     let out_dir = env::var("OUT_DIR").expect("Failed to get $OUT_DIR");
     let out_dir = Path::new(&out_dir);
     File::create(Path::new(out_dir).join("VERSION_CODE"))
@@ -2,12 +2,19 @@ use anyhow::Result;
 use const_format::concatcp;
 use rust_embed::RustEmbed;
 use std::path::Path;
+use std::process::Command;
 
 use crate::{defs::BINARY_DIR, utils};
 
 pub const RESETPROP_PATH: &str = concatcp!(BINARY_DIR, "resetprop");
 pub const BUSYBOX_PATH: &str = concatcp!(BINARY_DIR, "busybox");
 
+/// arch of the device, picked the same way as the embedded binaries below
+#[cfg(all(target_arch = "x86_64", target_os = "android"))]
+pub const DEVICE_ARCH: &str = "x86_64";
+#[cfg(not(all(target_arch = "x86_64", target_os = "android")))]
+pub const DEVICE_ARCH: &str = "arm64";
+
 #[cfg(all(target_arch = "x86_64", target_os = "android"))]
 #[derive(RustEmbed)]
 #[folder = "bin/x86_64"]
@@ -29,4 +36,17 @@ pub fn ensure_binaries(ignore_if_exist: bool) -> Result<()> {
         utils::ensure_binary(format!("{BINARY_DIR}{file}"), &asset.data, ignore_if_exist)?
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// version of the extracted busybox, e.g. 1.36.1, None if it can't be run
+pub fn busybox_version() -> Option<String> {
+    // the first line of its usage is "BusyBox v1.36.1 (2024-01-01 00:00:00 UTC) multi-call binary."
+    let output = Command::new(BUSYBOX_PATH).output().ok()?;
+    let usage = String::from_utf8_lossy(&output.stdout);
+    let version = usage
+        .split_whitespace()
+        .skip_while(|word| *word != "BusyBox")
+        .nth(1)?
+        .strip_prefix('v')?;
+    Some(version.to_string())
+}
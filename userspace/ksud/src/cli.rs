@@ -2,13 +2,8 @@ use anyhow::{Ok, Result};
 use clap::Parser;
 use std::path::Path;
 
-#[cfg(target_os = "android")]
-use android_logger::Config;
-#[cfg(target_os = "android")]
-use log::LevelFilter;
-
 use crate::defs::KSUD_VERBOSE_LOG_FILE;
-use crate::{apk_sign, assets, debug, defs, init_event, ksucalls, module, utils};
+use crate::{apk_sign, assets, debug, defs, init_event, ksucalls, logger, module, utils};
 
 /// KernelSU userspace cli
 #[derive(Parser, Debug)]
@@ -19,6 +14,14 @@ struct Args {
 
     #[arg(short, long, default_value_t = cfg!(debug_assertions))]
     verbose: bool,
+
+    /// never color the output, it is also disabled by NO_COLOR or when stdout is not a terminal
+    #[arg(long, global = true, default_value = "false")]
+    no_color: bool,
+
+    /// abort the command if it takes longer than <SECS> and exit with 124, 0 disables it
+    #[arg(long, global = true, value_name = "SECS")]
+    command_timeout: Option<u64>,
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -33,10 +36,28 @@ enum Commands {
     PostFsData,
 
     /// Trigger `service` event
-    Services,
+    Services {
+        /// refuse to run if post-fs-data didn't run in this boot, instead of only warning
+        #[arg(long, default_value = "false")]
+        strict: bool,
+    },
 
     /// Trigger `boot-complete` event
-    BootCompleted,
+    BootCompleted {
+        /// refuse to run if post-fs-data or service didn't run in this boot
+        #[arg(long, default_value = "false")]
+        strict: bool,
+    },
+
+    /// Trigger `post-unlock` event, credential encrypted storage is available
+    PostUnlock {
+        /// refuse to run if post-fs-data didn't run in this boot
+        #[arg(long, default_value = "false")]
+        strict: bool,
+    },
+
+    /// Trigger `on-shutdown` event, the device is shutting down or rebooting
+    OnShutdown,
 
     /// SELinux policy Patch tool
     Sepolicy {
@@ -50,6 +71,13 @@ enum Commands {
         command: Profile,
     },
     
+    /// Show the status of KernelSU and modules as json
+    Status {
+        /// keep running and print a new line whenever the status changes
+        #[arg(short, long, default_value = "false")]
+        watch: bool,
+    },
+
     /// For developers
     Debug {
         #[command(subcommand)]
@@ -66,6 +94,13 @@ enum Debug {
         apk: String,
     },
 
+    /// Check that the manager recognized by the kernel is the installed manager
+    VerifyManager {
+        /// manager package name
+        #[arg(default_value_t = String::from("me.weishu.kernelsu"))]
+        package: String,
+    },
+
     /// Get apk size and hash
     GetSign {
         /// apk path
@@ -82,7 +117,65 @@ enum Debug {
     /// Get kernel version
     Version,
 
-    Mount,
+    /// Show the build-time KernelSU options of the kernel
+    Config,
+
+    /// Check that binaries in <dir> are built for <arch> before embedding them
+    #[command(hide = true)]
+    CheckAssets {
+        /// directory of the binaries
+        dir: String,
+        /// aarch64 or x86_64
+        arch: String,
+    },
+
+    /// Show which binary the module scripts resolve each applet to
+    Applets {
+        /// applet names, defaults to the common ones
+        applets: Vec<String>,
+    },
+
+    /// Show SELinux mode, or set it to enforcing(1) or permissive(0)
+    Enforce {
+        /// enforcing state, 1 or 0
+        #[arg(value_parser = clap::builder::BoolishValueParser::new())]
+        state: Option<bool>,
+    },
+
+    Mount {
+        /// print what would be mounted instead of mounting it
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
+
+        /// print the dry-run plan as json
+        #[arg(long, default_value = "false", requires = "dry_run")]
+        json: bool,
+
+        /// plan the disabled modules too, as if they were enabled
+        #[arg(long, default_value = "false", requires = "dry_run")]
+        include_disabled: bool,
+    },
+
+    /// List every path the modules change in the merged system, with the module providing it
+    SystemChanges {
+        /// write a json tree instead of a file list
+        #[arg(long, default_value = "false")]
+        json: bool,
+
+        /// include the disabled modules, as if they were enabled
+        #[arg(long, default_value = "false")]
+        include_disabled: bool,
+
+        /// write the report to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+
+    /// Print the last log records of ksud in this boot, debug and trace only with verbose logging
+    RecentLogs,
+
+    /// Drop the dead entries of the module history, install state and app profiles, the old ones are kept as backups
+    Compact,
 
     /// For testing
     Test,
@@ -100,12 +193,27 @@ enum Sepolicy {
     Apply {
         /// sepolicy file path
         file: String,
+
+        /// apply a rule the file has more than once only once
+        #[arg(long, default_value = "false")]
+        dedup: bool,
     },
 
     /// Check if sepolicy statement is supported/valid
     Check {
         /// sepolicy statements
         sepolicy: String,
+
+        /// tell why each statement is invalid, and check types, classes and permissions against the live policy
+        #[arg(long, default_value = "false")]
+        explain: bool,
+    },
+
+    /// Show statistics of the live policy and the rules added by modules and profiles
+    Stats {
+        /// output as json
+        #[arg(long, default_value = "false")]
+        json: bool,
     },
 }
 
@@ -113,14 +221,90 @@ enum Sepolicy {
 enum Module {
     /// Install module <ZIP>
     Install {
+        /// module zip file path, or - to read it from stdin
+        zip: String,
+
+        /// for a bundle of module zips, continue installing the rest if one of them fails
+        #[arg(long, default_value = "false")]
+        continue_on_error: bool,
+
+        /// pin the signer of the module on first install; updates of a pinned module must always
+        /// be signed by the pinned signer
+        #[arg(long, default_value = "false")]
+        trust_tofu: bool,
+
+        /// with --trust-tofu, only pin a new signer which is accepted by --accept-signer
+        #[arg(long, default_value = "false", requires = "trust_tofu")]
+        tofu_strict: bool,
+
+        /// signer fingerprint(sha256 of the certificate) to trust
+        #[arg(long, requires = "trust_tofu")]
+        accept_signer: Option<String>,
+
+        /// apply the sepolicy rules, files and system.prop without reboot, a module which adds or
+        /// removes files or replaces directories is refused and applied on reboot only
+        #[arg(long, default_value = "false")]
+        now: bool,
+
+        /// fail if the module already has an update pending for reboot instead of replacing it
+        #[arg(long, default_value = "false")]
+        no_supersede: bool,
+
+        /// directory to stage the install in, defaults to $KSUD_TMPDIR or the system temp dir
+        #[arg(long)]
+        tmp_dir: Option<String>,
+
+        /// don't apply the sepolicy.rule of the module, print it for review instead
+        #[arg(long, default_value = "false")]
+        skip_sepolicy: bool,
+
+        /// leave out .git/, __MACOSX/, .DS_Store and the *.md at the top of the module
+        #[arg(long, default_value = "false")]
+        strip_junk: bool,
+
+        /// install even if the module conflicts with an installed one, per conflicts= in module.prop
+        #[arg(long, default_value = "false")]
+        ignore_conflicts: bool,
+
+        /// name of the zip read from stdin, used in logs and messages only
+        #[arg(long)]
+        stdin_zip_name: Option<String>,
+
+        /// reboot once installed, to recovery or bootloader if the module sets reboot= in module.prop
+        #[arg(long, default_value = "false")]
+        reboot: bool,
+
+        /// print the result as json, e.g. whether the module requires a reboot, as the last line
+        #[arg(long, default_value = "false")]
+        json: bool,
+    },
+
+    /// show the size, file count, id and version of a module zip without extracting it
+    ZipInfo {
         /// module zip file path
         zip: String,
+
+        /// print as json
+        #[arg(long, default_value = "false")]
+        json: bool,
     },
 
     /// Uninstall module <id>
     Uninstall {
         /// module id
         id: String,
+
+        /// uninstall it even if the module is protected
+        #[arg(short, long, default_value = "false")]
+        force: bool,
+
+        /// show which files would be revealed, restored or removed instead of uninstalling
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
+
+        /// print the dry-run result as json
+        #[arg(long, default_value = "false", requires = "dry_run")]
+        json: bool,
     },
 
     /// enable module <id>
@@ -133,16 +317,141 @@ enum Module {
     Disable {
         // module id
         id: String,
+
+        /// disable it even if the module is protected
+        #[arg(short, long, default_value = "false")]
+        force: bool,
     },
 
     /// run action for module <id>
     Action {
         // module id
         id: String,
+
+        /// print the environment and argv of action.sh instead of running it
+        #[arg(long, default_value = "false")]
+        dump_env: bool,
+    },
+
+    /// skip the <stage> script of module <id>, the rest of the module keeps working
+    SkipStage {
+        /// module id
+        id: String,
+
+        /// boot stage whose script to skip
+        #[arg(value_enum)]
+        stage: module::ModuleStage,
+
+        /// run the script of the stage again
+        #[arg(long, default_value = "false")]
+        undo: bool,
+    },
+
+    /// freeze the processes started by service.sh of module <id>
+    PauseServices {
+        /// module id
+        id: String,
+    },
+
+    /// continue the processes paused by pause-services
+    ResumeServices {
+        /// module id
+        id: String,
+    },
+
+    /// list the files module <id> provides to the overlay, + added and - removed by customize.sh
+    Files {
+        /// module id
+        id: String,
+
+        /// print as json
+        #[arg(long, default_value = "false")]
+        json: bool,
+    },
+
+    /// check that the webroot of module <id> has an index.html, and its files against the hash
+    /// manifest of its zip, which is not signed and only catches files changed since the install
+    Verify {
+        /// module id
+        id: String,
+    },
+
+    /// Check the files under <dir> against a hash manifest, installer.sh runs it on $MODPATH
+    #[command(hide = true)]
+    CheckHashes {
+        /// hash manifest path
+        manifest: String,
+        /// directory of the extracted module
+        dir: String,
+        /// only check the files which are there, not that every listed file is
+        #[arg(long, default_value = "false")]
+        partial: bool,
+    },
+
+    /// show module.prop and the state of module <id>
+    Info {
+        /// module id
+        id: String,
+
+        /// print the output of customize.sh from the last install instead
+        #[arg(long, default_value = "false")]
+        show_install_log: bool,
+    },
+
+    /// show or set how many boots may fail in a row before all modules are disabled
+    Watchdog {
+        /// new threshold, 0 turns the boot watchdog off
+        #[arg(long)]
+        threshold: Option<u32>,
+    },
+
+    /// show the recent install, uninstall, enable and disable events of modules
+    History {
+        /// print as json
+        #[arg(long, default_value = "false")]
+        json: bool,
+    },
+
+    /// serve the webroot of module <id> on localhost until interrupted
+    Serve {
+        /// module id
+        id: String,
+
+        /// port to listen on, 0 picks a free one
+        #[arg(short, long, default_value = "0")]
+        port: u16,
     },
 
     /// list all modules
-    List,
+    List {
+        /// sort order of the modules
+        #[arg(long, value_enum, default_value = "id")]
+        sort: module::ModuleSort,
+
+        /// print the number of total, enabled and disabled modules instead of the list
+        #[arg(long, default_value = "false")]
+        count: bool,
+
+        /// print `{schema, modules}` instead of the bare array
+        #[arg(long, default_value = "false", conflicts_with = "count")]
+        json: bool,
+    },
+
+    /// check the updateJson of module <ID> for a newer version, busybox wget doesn't check TLS
+    /// certificates so nothing it fetches is verified
+    CheckUpdate {
+        /// module id
+        id: String,
+
+        /// also show the module.prop of the new zip and the changelog, the zip is only read as
+        /// far as needed if the server supports range requests
+        #[arg(long, default_value = "false")]
+        preview: bool,
+
+        /// print as json
+        #[arg(long, default_value = "false")]
+        json: bool,
+    },
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -159,12 +468,18 @@ enum Profile {
         package: String,
         /// policy statements
         policy: String,
+        /// check the policy and print the statements it adds and removes, without saving it
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
     },
 
     /// get template of <id>
     GetTemplate {
         /// template id
         id: String,
+        /// merge in the fields of the templates it extends, `inherits` lists the chain
+        #[arg(long, default_value = "false")]
+        resolved: bool,
     },
 
     /// set template of <id> to <template string>
@@ -173,46 +488,77 @@ enum Profile {
         id: String,
         /// template string
         template: String,
+        /// print the current and the new template, without saving it
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
     },
 
     /// delete template of <id>
     DeleteTemplate {
         /// template id
         id: String,
+
+        /// delete it even if other templates extend it
+        #[arg(long, default_value = "false")]
+        force: bool,
     },
 
     /// list all templates
     ListTemplates,
+
+    /// check the kernel allow list against the installed apps and stored profiles, fix the drift
+    Reconcile {
+        /// only report what would be fixed
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
+    },
+
+    /// export all root profile's selinux policies and templates
+    Export {
+        /// output format
+        #[arg(long, value_enum, default_value = "json")]
+        format: crate::profile::ExportFormat,
+    },
 }
 
 pub fn run() -> Result<()> {
-    #[cfg(target_os = "android")]
-    android_logger::init_once(
-        Config::default()
-            .with_max_level(LevelFilter::Trace) // limit log level
-            .with_tag("KernelSU"), // logs will show under mytag tag
-    );
-
-    #[cfg(not(target_os = "android"))]
-    env_logger::init();
+    logger::init();
 
     // the kernel executes su with argv[0] = "su" and replace it with us
     let arg0 = std::env::args().next().unwrap_or_default();
+    // su runs far too often to keep its records, they would push out the ones of ksud
     if arg0 == "su" || arg0 == "/system/bin/su" {
+        if !Path::new(KSUD_VERBOSE_LOG_FILE).exists() {
+            logger::set_verbose(false);
+        }
         return crate::su::root_shell();
     }
 
     let cli = Args::parse();
 
     if !cli.verbose && !Path::new(KSUD_VERBOSE_LOG_FILE).exists() {
-        log::set_max_level(LevelFilter::Info);
+        logger::set_verbose(false);
+    }
+
+    if cli.no_color {
+        utils::disable_color();
+    }
+
+    if let Some(secs) = cli.command_timeout.filter(|secs| *secs > 0) {
+        if !utils::is_timeout_child() {
+            let result = utils::run_with_timeout(std::time::Duration::from_secs(secs));
+            logger::save();
+            std::process::exit(result?);
+        }
     }
 
     log::info!("command: {:?}", cli.command);
 
     let result = match cli.command {
         Commands::PostFsData => init_event::on_post_data_fs(),
-        Commands::BootCompleted => init_event::on_boot_completed(),
+        Commands::BootCompleted { strict } => init_event::on_boot_completed(strict),
+        Commands::PostUnlock { strict } => init_event::on_post_unlock(strict),
+        Commands::OnShutdown => init_event::on_shutdown(),
 
         Commands::Module { command } => {
             #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -221,35 +567,117 @@ pub fn run() -> Result<()> {
                 utils::unshare_mnt_ns()?;
             }
             match command {
-                Module::Install { zip } => module::install_module(&zip),
-                Module::Uninstall { id } => module::uninstall_module(&id),
+                Module::Install {
+                    zip,
+                    continue_on_error,
+                    trust_tofu,
+                    tofu_strict,
+                    accept_signer,
+                    now,
+                    no_supersede,
+                    tmp_dir,
+                    skip_sepolicy,
+                    strip_junk,
+                    ignore_conflicts,
+                    stdin_zip_name,
+                    reboot,
+                    json,
+                } => module::install_module(
+                    &zip,
+                    &module::InstallOptions {
+                        continue_on_error,
+                        trust_tofu,
+                        tofu_strict,
+                        accept_signer,
+                        now,
+                        no_supersede,
+                        tmp_dir,
+                        skip_sepolicy,
+                        strip_junk,
+                        ignore_conflicts,
+                        stdin_zip_name,
+                        reboot,
+                        json,
+                    },
+                ),
+                Module::Uninstall {
+                    id,
+                    force,
+                    dry_run,
+                    json,
+                } => {
+                    if dry_run {
+                        module::uninstall_dry_run(&id, json)
+                    } else {
+                        module::uninstall_module(&id, force)
+                    }
+                }
                 Module::Enable { id } => module::enable_module(&id),
-                Module::Disable { id } => module::disable_module(&id),
-                Module::Action { id } => module::run_action(&id),
-                Module::List => module::list_modules(),
+                Module::Disable { id, force } => module::disable_module(&id, force),
+                Module::Action { id, dump_env } => module::run_action(&id, dump_env),
+                Module::SkipStage { id, stage, undo } => module::skip_stage(&id, stage, !undo),
+                Module::Verify { id } => module::verify_module(&id),
+                Module::CheckHashes {
+                    manifest,
+                    dir,
+                    partial,
+                } => module::check_hashes(&manifest, &dir, partial),
+                Module::ZipInfo { zip, json } => module::zip_info(&zip, json),
+                Module::Files { id, json } => module::module_files(&id, json),
+                Module::PauseServices { id } => module::pause_services(&id, true),
+                Module::ResumeServices { id } => module::pause_services(&id, false),
+                Module::Info {
+                    id,
+                    show_install_log,
+                } => module::module_info(&id, show_install_log),
+                Module::Watchdog { threshold } => module::boot_watchdog(threshold),
+                Module::History { json } => module::show_history(json),
+                Module::Serve { id, port } => crate::webui::serve(&id, port),
+                Module::List { sort, count, json } => module::list_modules(sort, count, json),
+                Module::CheckUpdate { id, preview, json } => {
+                    module::check_update(&id, preview, json)
+                }
             }
         },
         
         Commands::Sepolicy { command } => match command {
             Sepolicy::Patch { sepolicy } => crate::sepolicy::live_patch(&sepolicy),
-            Sepolicy::Apply { file } => crate::sepolicy::apply_file(file),
-            Sepolicy::Check { sepolicy } => crate::sepolicy::check_rule(&sepolicy),
+            Sepolicy::Apply { file, dedup } => crate::sepolicy::apply_file(file, dedup),
+            Sepolicy::Check { sepolicy, explain } => {
+                if explain {
+                    crate::sepolicy::explain_rule(&sepolicy)
+                } else {
+                    crate::sepolicy::check_rule(&sepolicy)
+                }
+            }
+            Sepolicy::Stats { json } => crate::sepolicy::stats(json),
         },
         
-        Commands::Services => init_event::on_services(),
+        Commands::Services { strict } => init_event::on_services(strict),
         Commands::Profile { command } => match command {
             Profile::GetSepolicy { package } => crate::profile::get_sepolicy(package),
-            Profile::SetSepolicy { package, policy } => {
-                crate::profile::set_sepolicy(package, policy)
-            }
-            Profile::GetTemplate { id } => crate::profile::get_template(id),
-            Profile::SetTemplate { id, template } => crate::profile::set_template(id, template),
-            Profile::DeleteTemplate { id } => crate::profile::delete_template(id),
+            Profile::SetSepolicy {
+                package,
+                policy,
+                dry_run,
+            } => crate::profile::set_sepolicy(package, policy, dry_run),
+            Profile::GetTemplate { id, resolved } => crate::profile::get_template(id, resolved),
+            Profile::SetTemplate {
+                id,
+                template,
+                dry_run,
+            } => crate::profile::set_template(id, template, dry_run),
+            Profile::DeleteTemplate { id, force } => crate::profile::delete_template(id, force),
             Profile::ListTemplates => crate::profile::list_templates(),
+            Profile::Export { format } => crate::profile::export(format),
+            Profile::Reconcile { dry_run } => crate::profile::reconcile(dry_run),
         },
 
+        Commands::Status { watch } => crate::status::status(watch),
+
         Commands::Debug { command } => match command {
             Debug::SetManager { apk } => debug::set_manager(&apk),
+            Debug::VerifyManager { package } => debug::verify_manager(&package),
             Debug::GetSign { apk } => {
                 let sign = apk_sign::get_apk_signature(&apk)?;
                 println!("size: {:#x}, hash: {}", sign.0, sign.1);
@@ -257,10 +685,35 @@ pub fn run() -> Result<()> {
             }
             Debug::Version => {
                 println!("Kernel Version: {}", ksucalls::get_version());
+                println!(
+                    "Busybox Version: {}",
+                    assets::busybox_version().as_deref().unwrap_or("unknown")
+                );
                 Ok(())
             }
+            Debug::Enforce { state } => debug::enforce(state),
+            Debug::Applets { applets } => debug::show_applets(&applets),
+            Debug::Config => debug::show_config(),
+            Debug::CheckAssets { dir, arch } => debug::check_assets(&dir, &arch),
             Debug::Su { global_mnt } => crate::su::grant_root(global_mnt),
-            Debug::Mount => init_event::mount_modules_systemlessly(),
+            Debug::Mount {
+                dry_run,
+                json,
+                include_disabled,
+            } => {
+                if dry_run {
+                    debug::mount_plan(json, include_disabled)
+                } else {
+                    init_event::mount_modules_systemlessly()
+                }
+            }
+            Debug::SystemChanges {
+                json,
+                include_disabled,
+                output,
+            } => debug::system_changes(json, include_disabled, output.as_deref()),
+            Debug::RecentLogs => logger::print_recent_logs(),
+            Debug::Compact => module::compact_state(),
             Debug::Test => assets::ensure_binaries(false),
         },
     };
@@ -268,5 +721,6 @@ pub fn run() -> Result<()> {
     if let Err(e) = &result {
         log::error!("Error: {:?}", e);
     }
+    logger::save();
     result
 }
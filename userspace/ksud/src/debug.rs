@@ -1,10 +1,34 @@
-use anyhow::{ensure, Context, Ok, Result};
+use anyhow::{bail, ensure, Context, Ok, Result};
 use std::{
+    collections::HashSet,
     path::{Path, PathBuf},
     process::Command,
 };
 
+use crate::{apk_sign, assets, ksucalls, module};
+
 const KERNEL_PARAM_PATH: &str = "/sys/module/kernelsu";
+const SELINUX_ENFORCE_PATH: &str = "/sys/fs/selinux/enforce";
+const DEFAULT_APPLETS: &[&str] = &[
+    "sh",
+    "mount",
+    "umount",
+    "mkdir",
+    "cp",
+    "mv",
+    "rm",
+    "ln",
+    "chmod",
+    "chown",
+    "chcon",
+    "cat",
+    "grep",
+    "sed",
+    "awk",
+    "find",
+    "unzip",
+    "resetprop",
+];
 
 fn read_u32(path: &PathBuf) -> Result<u32> {
     let content = std::fs::read_to_string(path)?;
@@ -35,6 +59,63 @@ fn get_pkg_uid(pkg: &str) -> Result<u32> {
     Ok(uid)
 }
 
+// base.apk of an installed package
+fn installed_apk_path(pkg: &str) -> Option<String> {
+    let output = Command::new("pm").args(["path", pkg]).output().ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.strip_prefix("package:"))
+        .find(|path| path.ends_with("/base.apk"))
+        .map(str::to_owned)
+}
+
+/// compare the manager the kernel recognized with the installed package `pkg`
+pub fn verify_manager(pkg: &str) -> Result<()> {
+    #[cfg(target_os = "android")]
+    let installed_uid = get_pkg_uid(pkg).ok();
+    #[cfg(not(target_os = "android"))]
+    let installed_uid: Option<u32> = None;
+    let kernel_uid = ksucalls::get_manager_uid();
+
+    println!("package: {pkg}");
+    match installed_uid {
+        Some(uid) => println!("installed uid: {uid}"),
+        None => println!("installed uid: not installed"),
+    }
+    match kernel_uid {
+        Some(Some(uid)) => println!("kernel manager uid: {uid}"),
+        Some(None) => println!("kernel manager uid: none"),
+        None => println!("kernel manager uid: unknown, the kernel doesn't support the query"),
+    }
+
+    match installed_apk_path(pkg) {
+        Some(apk) => {
+            println!("apk: {apk}");
+            let sign = apk_sign::get_apk_signature(&apk).map_or_else(
+                |e| format!("failed to read it: {e}"),
+                |(size, hash)| format!("size: {size:#x}, hash: {hash}"),
+            );
+            println!("signature: {sign}");
+        }
+        None => println!("apk: not found"),
+    }
+
+    let installed_uid = installed_uid.with_context(|| format!("{pkg} is not installed"))?;
+    match kernel_uid {
+        Some(Some(uid)) => ensure!(
+            uid % 100_000 == installed_uid % 100_000,
+            "mismatch: the kernel recognized uid {uid} as the manager, but {pkg} has uid {installed_uid}"
+        ),
+        // the kernel only accepts a manager signed with the key it was built for
+        Some(None) => bail!(
+            "mismatch: the kernel recognized no manager, likely the signature above is not the one it was built for"
+        ),
+        None => bail!("unknown: the kernel doesn't report its manager"),
+    }
+    println!("match");
+    Ok(())
+}
+
 pub fn set_manager(pkg: &str) -> Result<()> {
     ensure!(
         Path::new(KERNEL_PARAM_PATH).exists(),
@@ -50,3 +131,275 @@ pub fn set_manager(pkg: &str) -> Result<()> {
     let _ = Command::new("am").args(["force-stop", pkg]).status();
     Ok(())
 }
+
+fn get_enforce() -> Result<bool> {
+    let content = std::fs::read_to_string(SELINUX_ENFORCE_PATH)
+        .with_context(|| format!("read {SELINUX_ENFORCE_PATH}"))?;
+    Ok(content.trim() == "1")
+}
+
+fn mode_name(enforcing: bool) -> &'static str {
+    if enforcing {
+        "Enforcing"
+    } else {
+        "Permissive"
+    }
+}
+
+pub fn enforce(state: Option<bool>) -> Result<()> {
+    ensure!(
+        Path::new(SELINUX_ENFORCE_PATH).exists(),
+        "SELinux is disabled or selinuxfs is not mounted"
+    );
+
+    let current = get_enforce()?;
+    let Some(state) = state else {
+        println!("{}", mode_name(current));
+        return Ok(());
+    };
+
+    if state == current {
+        println!("SELinux is already {}", mode_name(current));
+        return Ok(());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    ensure!(
+        rustix::process::getuid().is_root(),
+        "root is required to change SELinux mode"
+    );
+
+    // the su domain is only usable in enforcing mode when the kernel patched the policy for us,
+    // otherwise we would lose the root shell once SELinux is enforced.
+    ensure!(
+        !state || ksucalls::get_version() > 0,
+        "KernelSU is not working in kernel, refuse to switch SELinux to Enforcing"
+    );
+
+    std::fs::write(SELINUX_ENFORCE_PATH, if state { "1" } else { "0" })
+        .with_context(|| format!("write {SELINUX_ENFORCE_PATH}"))?;
+    let after = get_enforce()?;
+    log::info!(
+        "SELinux mode: {} -> {}",
+        mode_name(current),
+        mode_name(after)
+    );
+    println!("{} -> {}", mode_name(current), mode_name(after));
+    ensure!(after == state, "kernel refused to change SELinux mode");
+
+    Ok(())
+}
+
+pub fn show_applets(applets: &[String]) -> Result<()> {
+    let output = Command::new(assets::BUSYBOX_PATH)
+        .arg("--list")
+        .output()
+        .with_context(|| format!("Failed to exec {} --list", assets::BUSYBOX_PATH))?;
+    let busybox_applets: HashSet<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::to_owned)
+        .collect();
+
+    let path = module::get_script_path_env();
+    let cwd = std::env::current_dir()?;
+    let applets = if applets.is_empty() {
+        DEFAULT_APPLETS.iter().map(ToString::to_string).collect()
+    } else {
+        applets.to_vec()
+    };
+
+    println!("PATH={path}");
+    for applet in &applets {
+        let in_path = which::which_in(applet, Some(&path), &cwd).ok();
+        let in_path_str = in_path
+            .as_ref()
+            .map_or("not found".to_string(), |p| p.display().to_string());
+
+        // module scripts run in busybox ash standalone mode, its applets always win over PATH
+        if !busybox_applets.contains(applet) {
+            println!("{applet}: {in_path_str}");
+        } else if in_path.is_some_and(|p| p != Path::new(assets::BUSYBOX_PATH)) {
+            println!(
+                "{applet}: {} (applet), PATH: {in_path_str} [!] busybox is not first in PATH",
+                assets::BUSYBOX_PATH
+            );
+        } else {
+            println!("{applet}: {} (applet)", assets::BUSYBOX_PATH);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn show_config() -> Result<()> {
+    let Some(flags) = ksucalls::get_config_flags() else {
+        println!("unknown, the kernel doesn't support querying config flags");
+        return Ok(());
+    };
+
+    for (flag, name) in [
+        (ksucalls::CONFIG_DEBUG, "CONFIG_KSU_DEBUG"),
+        (ksucalls::CONFIG_CMDLINE, "CONFIG_KSU_CMDLINE"),
+        (ksucalls::CONFIG_KPROBES, "CONFIG_KPROBES"),
+        (ksucalls::CONFIG_LKM, "LKM"),
+        (ksucalls::CONFIG_UMOUNT, "UMOUNT"),
+    ] {
+        println!("{name}: {}", if flags & flag != 0 { "y" } else { "n" });
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "android")]
+pub fn mount_plan(json: bool, include_disabled: bool) -> Result<()> {
+    let plan = crate::magic_mount::mount_plan(include_disabled)?;
+    if json {
+        let plan: Vec<_> = plan
+            .iter()
+            .map(|entry| {
+                serde_json::json!({
+                    "module": entry.module,
+                    "source": entry.source,
+                    "target": entry.target,
+                    "fstype": entry.fstype,
+                    "options": entry.options,
+                    "disabled": entry.disabled,
+                })
+            })
+            .collect();
+        return crate::utils::print_json(serde_json::json!({ "plan": plan }), true);
+    }
+
+    for entry in &plan {
+        let options = if entry.options.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", entry.options)
+        };
+        let disabled = if entry.disabled {
+            " [would mount if enabled]"
+        } else {
+            ""
+        };
+        println!(
+            "[{}] {} {} -> {}{options}{disabled}",
+            entry.module, entry.fstype, entry.source, entry.target
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "android"))]
+pub fn mount_plan(_json: bool, _include_disabled: bool) -> Result<()> {
+    bail!("the mount plan is only supported on Android")
+}
+
+// how the path differs from the base system once mounted, a tmpfs which only mirrors the
+// original dir to make room for module files doesn't change the dir itself
+#[cfg(target_os = "android")]
+fn change_of(entry: &crate::magic_mount::MountPlanEntry) -> Option<&'static str> {
+    match entry.fstype {
+        "bind" => Some("file"),
+        "symlink" => Some("symlink"),
+        "whiteout" => Some("removed"),
+        "tmpfs" if entry.options == "replace" => Some("replaced"),
+        _ => None,
+    }
+}
+
+// /system/bin/foo -> tree["system"]["children"]["bin"]["children"]["foo"]
+#[cfg(target_os = "android")]
+fn insert_change(
+    tree: &mut serde_json::Map<String, serde_json::Value>,
+    target: &str,
+    change: serde_json::Value,
+) {
+    let names: Vec<&str> = target.split('/').filter(|name| !name.is_empty()).collect();
+    let Some((last, parents)) = names.split_last() else {
+        return;
+    };
+    let mut children = tree;
+    for name in parents {
+        let node = children
+            .entry(*name)
+            .or_insert_with(|| serde_json::json!({}));
+        let Some(node_children) = node.as_object_mut().and_then(|node| {
+            node.entry("children")
+                .or_insert_with(|| serde_json::json!({}))
+                .as_object_mut()
+        }) else {
+            return;
+        };
+        children = node_children;
+    }
+    let node = children
+        .entry(*last)
+        .or_insert_with(|| serde_json::json!({}));
+    if let (Some(node), serde_json::Value::Object(change)) = (node.as_object_mut(), change) {
+        node.extend(change);
+    }
+}
+
+/// Every path the modules change, add or remove once mounted, with the module which provides it,
+/// as a list or a json tree, printed or written to `output`.
+#[cfg(target_os = "android")]
+pub fn system_changes(json: bool, include_disabled: bool, output: Option<&str>) -> Result<()> {
+    let plan = crate::magic_mount::mount_plan(include_disabled)?;
+    let changes: Vec<_> = plan
+        .iter()
+        .filter_map(|entry| change_of(entry).map(|change| (change, entry)))
+        .collect();
+
+    let report = if json {
+        let mut tree = serde_json::Map::new();
+        for (change, entry) in &changes {
+            let mut fields = serde_json::json!({
+                "change": change,
+                "module": entry.module,
+                "disabled": entry.disabled,
+            });
+            if *change == "symlink" {
+                fields["source"] = entry.source.clone().into();
+            }
+            insert_change(&mut tree, &entry.target, fields);
+        }
+        let report = crate::utils::with_schema(serde_json::json!({ "tree": tree }));
+        serde_json::to_string_pretty(&report)? + "\n"
+    } else {
+        changes
+            .iter()
+            .map(|(change, entry)| {
+                let disabled = if entry.disabled { " [if enabled]" } else { "" };
+                format!("[{}] {change} {}{disabled}\n", entry.module, entry.target)
+            })
+            .collect()
+    };
+
+    match output {
+        Some(output) => {
+            std::fs::write(output, report)
+                .with_context(|| format!("Failed to write the report to {output}"))?;
+            println!("- {} changed paths written to {output}", changes.len());
+        }
+        None => print!("{report}"),
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "android"))]
+pub fn system_changes(_json: bool, _include_disabled: bool, _output: Option<&str>) -> Result<()> {
+    bail!("system changes are only supported on Android")
+}
+
+pub fn check_assets(dir: &str, arch: &str) -> Result<()> {
+    let mismatched = crate::elf_arch::check_dir(Path::new(dir), arch)?;
+    for binary in &mismatched {
+        println!("{binary}");
+    }
+    ensure!(
+        mismatched.is_empty(),
+        "{} binaries in {dir} are not built for {arch}",
+        mismatched.len()
+    );
+    println!("all binaries in {dir} are built for {arch}");
+    Ok(())
+}
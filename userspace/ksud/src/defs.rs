@@ -1,16 +1,32 @@
 use const_format::concatcp;
 
+// On FBE devices /data/adb is device encrypted(DE) storage, which is available since post-fs-data.
+// Credential encrypted(CE) storage, such as /data/data and /data/user/<id>, is only available once
+// the user unlocked the device, so it must not be touched before the post-unlock stage.
 pub const ADB_DIR: &str = "/data/adb/";
 pub const WORKING_DIR: &str = concatcp!(ADB_DIR, "ksu/");
 pub const BINARY_DIR: &str = concatcp!(WORKING_DIR, "bin/");
 pub const LOG_DIR: &str = concatcp!(WORKING_DIR, "log/");
 
+pub const MODULE_SIGNER_DIR: &str = concatcp!(WORKING_DIR, "module_signers/");
+pub const MODULE_INSTALL_STATE_DIR: &str = concatcp!(WORKING_DIR, "module_install/");
+pub const MODULE_HISTORY_FILE: &str = concatcp!(WORKING_DIR, "module_history");
+
 pub const PROFILE_DIR: &str = concatcp!(WORKING_DIR, "profile/");
 pub const PROFILE_SELINUX_DIR: &str = concatcp!(PROFILE_DIR, "selinux/");
 pub const PROFILE_TEMPLATE_DIR: &str = concatcp!(PROFILE_DIR, "templates/");
+// the profiles dropped by debug compact, outside PROFILE_DIR so they are never applied
+pub const PROFILE_BACKUP_DIR: &str = concatcp!(WORKING_DIR, "profile.bak/");
+// written by the kernel, see kernel/allowlist.c
+pub const KERNEL_ALLOWLIST_FILE: &str = concatcp!(WORKING_DIR, ".allowlist");
 
 pub const KSURC_PATH: &str = concatcp!(WORKING_DIR, ".ksurc");
+// boots in a row which didn't reach boot-completed, and how many of them disable all modules
+pub const BOOT_ATTEMPTS_FILE: &str = concatcp!(WORKING_DIR, ".boot_attempts");
+pub const BOOT_WATCHDOG_THRESHOLD_FILE: &str = concatcp!(WORKING_DIR, "boot_watchdog_threshold");
 pub const KSU_MOUNT_SOURCE: &str = "KSU";
+// a module dir with this xattr set to "y" replaces the stock dir instead of merging into it
+pub const REPLACE_DIR_XATTR: &str = "trusted.overlay.opaque";
 pub const DAEMON_PATH: &str = concatcp!(ADB_DIR, "ksud");
 
 pub const MODULE_DIR: &str = concatcp!(ADB_DIR, "modules/");
@@ -29,6 +45,22 @@ pub const UPDATE_FILE_NAME: &str = "update";
 pub const REMOVE_FILE_NAME: &str = "remove";
 pub const SKIP_MOUNT_FILE_NAME: &str = "skip_mount";
 pub const MAGIC_MOUNT_WORK_DIR: &str = concatcp!(TEMP_DIR, "/workdir");
+// the boot stages which ran in this boot, one per line
+pub const BOOT_STAGES_FILE: &str = concatcp!(TEMP_DIR, "/boot_stages");
+// the last records of the ksud commands in this boot, TEMP_DIR is a tmpfs so it never reaches the disk
+pub const RECENT_LOGS_FILE: &str = concatcp!(TEMP_DIR, "/ksud_recent.log");
+// created once boot-completed scripts ran in this boot, TEMP_DIR is a tmpfs
+pub const BOOT_COMPLETED_MARKER: &str = concatcp!(TEMP_DIR, "/boot_completed");
+// process group of the service.sh of each module in this boot, TEMP_DIR is a tmpfs
+pub const MODULE_SERVICE_PGID_DIR: &str = concatcp!(TEMP_DIR, "/services/");
+
+/// The "schema" field of the json objects ksud prints, bumped whenever one of them changes
+/// incompatibly, e.g. a field is removed, renamed or changes its type. Adding a field is not.
+/// Version 1: status, module install --json, module list --json, module uninstall --dry-run,
+/// module zip-info, module files, module history --json, module check-update --json,
+/// debug mount --dry-run, debug system-changes --json, sepolicy stats, profile export,
+/// profile get-template --resolved, the WebUI bridge. Without --json module list stays a bare array for older managers.
+pub const JSON_SCHEMA_VERSION: u32 = 1;
 
 pub const VERSION_CODE: &str = include_str!(concat!(env!("OUT_DIR"), "/VERSION_CODE"));
 pub const VERSION_NAME: &str = include_str!(concat!(env!("OUT_DIR"), "/VERSION_NAME"));
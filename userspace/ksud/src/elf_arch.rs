@@ -0,0 +1,55 @@
+// ELF architecture check of the embedded binaries, this file is shared with build.rs
+use std::{
+    fs::File,
+    io::{Error, ErrorKind, Read, Result},
+    path::Path,
+};
+
+const EM_AARCH64: u16 = 183;
+const EM_X86_64: u16 = 62;
+
+fn machine_of_arch(arch: &str) -> Option<u16> {
+    match arch {
+        "aarch64" => Some(EM_AARCH64),
+        "x86_64" => Some(EM_X86_64),
+        _ => None,
+    }
+}
+
+// returns None if it is not an ELF file, i.e. it doesn't start with the ELF magic
+fn read_elf_machine(path: &Path) -> Result<Option<u16>> {
+    let mut header = Vec::with_capacity(20);
+    File::open(path)?.take(20).read_to_end(&mut header)?;
+    if !header.starts_with(b"\x7fELF") {
+        return Ok(None);
+    }
+    if header.len() < 20 {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "truncated ELF header"));
+    }
+    // e_machine follows e_ident and e_type, both of the arches we ship are little endian
+    Ok(Some(u16::from_le_bytes([header[18], header[19]])))
+}
+
+/// check that every ELF binary in `dir` is built for `arch`, returns the mismatched ones.
+/// Other files, e.g. scripts, are skipped.
+pub fn check_dir(dir: &Path, arch: &str) -> Result<Vec<String>> {
+    let expected = machine_of_arch(arch)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("unknown arch: {arch}")))?;
+    let mut mismatched = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if !path.is_file() || entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        match read_elf_machine(&path) {
+            Ok(Some(machine)) if machine == expected => {}
+            Ok(Some(machine)) => {
+                mismatched.push(format!("{}: e_machine is {machine}", path.display()));
+            }
+            Ok(None) => {}
+            Err(e) => mismatched.push(format!("{}: {e}", path.display())),
+        }
+    }
+    Ok(mismatched)
+}
@@ -0,0 +1,229 @@
+// HTTP(S) downloads through the wget applet of busybox, ksud has no network stack of its own.
+// wget refuses a partial response unless it continues a file with -c, so a range is read by
+// letting it continue a sparse file as long as the offset and stopping it once it has enough.
+use anyhow::{bail, ensure, Context, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::process::{Child, ChildStderr, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::assets;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+// ranges are read in blocks, the central directory of a module zip usually fits in one
+const BLOCK_SIZE: u64 = 64 * 1024;
+
+fn wget() -> Command {
+    let mut command = Command::new(assets::BUSYBOX_PATH);
+    command.args(["wget", "-q", "-T", &TIMEOUT.as_secs().to_string()]);
+    command
+}
+
+/// Fetch `url` as a whole, for small documents like an updateJson or a changelog.
+pub fn get(url: &str) -> Result<Vec<u8>> {
+    let output = wget()
+        .args(["-O", "-", url])
+        .stderr(Stdio::piped())
+        .output()
+        .with_context(|| format!("Failed to exec {} wget", assets::BUSYBOX_PATH))?;
+    ensure!(
+        output.status.success(),
+        "Failed to fetch {url}: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(output.stdout)
+}
+
+struct Response {
+    status: u16,
+    // lowercase names
+    headers: BTreeMap<String, String>,
+}
+
+// -S prints the status line and headers of every response, each followed by an empty line,
+// the ones of redirects come first
+fn read_response(stderr: &mut BufReader<ChildStderr>) -> Result<Response> {
+    let mut response = Response {
+        status: 0,
+        headers: BTreeMap::new(),
+    };
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if stderr.read_line(&mut line)? == 0 {
+            bail!("no response");
+        }
+        // what -S prints is indented, the rest is an error of wget itself; after the status of
+        // the final response the caller tells what went wrong better, e.g. a range was ignored
+        let Some(line) = line.strip_prefix("  ") else {
+            if response.status / 100 == 2 {
+                return Ok(response);
+            }
+            bail!("{}", line.trim());
+        };
+        let line = line.trim();
+        if let Some(status_line) = line.strip_prefix("HTTP/") {
+            response.status = status_line
+                .split_whitespace()
+                .nth(1)
+                .and_then(|status| status.parse().ok())
+                .unwrap_or(0);
+            response.headers.clear();
+        } else if let Some((name, value)) = line.split_once(':') {
+            response
+                .headers
+                .insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        } else if line.is_empty() && !(300..400).contains(&response.status) {
+            return Ok(response);
+        }
+    }
+}
+
+fn stop(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+// the bytes of `url` in [offset, offset + len), fewer if the file ends before; `file` is a
+// scratch file which is overwritten
+fn fetch_range(url: &str, file: &Path, offset: u64, len: u64) -> Result<(Vec<u8>, Response)> {
+    File::create(file)?.set_len(offset)?;
+    let mut child = wget()
+        .args(["-S", "-c", "-O"])
+        .arg(file)
+        .arg(url)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to exec {} wget", assets::BUSYBOX_PATH))?;
+    let mut stderr = BufReader::new(child.stderr.take().context("no stderr of wget")?);
+    let response = match read_response(&mut stderr) {
+        Ok(response) => response,
+        Err(e) => {
+            stop(&mut child);
+            return Err(e).with_context(|| format!("Failed to fetch {url}"));
+        }
+    };
+    // without a Range header, i.e. at offset 0, the whole file comes back
+    if offset > 0 && response.status != 206 {
+        stop(&mut child);
+        bail!(
+            "{url} doesn't support range requests, status {}",
+            response.status
+        );
+    }
+
+    let start = Instant::now();
+    let wanted = offset + len;
+    loop {
+        if file.metadata()?.len() >= wanted || child.try_wait()?.is_some() {
+            break;
+        }
+        if start.elapsed() > TIMEOUT {
+            stop(&mut child);
+            bail!("Timed out fetching {url}");
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    stop(&mut child);
+
+    let mut data = Vec::new();
+    let mut file = File::open(file)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.take(len).read_to_end(&mut data)?;
+    Ok((data, response))
+}
+
+/// A file on a server which supports range requests, only the blocks which are read are
+/// downloaded.
+pub struct RemoteFile {
+    url: String,
+    len: u64,
+    pos: u64,
+    blocks: BTreeMap<u64, Vec<u8>>,
+    scratch: tempdir::TempDir,
+}
+
+impl RemoteFile {
+    pub fn open(url: &str) -> Result<Self> {
+        let scratch = tempdir::TempDir::new("ksud_http")?;
+        let (first, response) = fetch_range(url, &scratch.path().join("range"), 0, BLOCK_SIZE)?;
+        ensure!(
+            response.status == 200,
+            "Failed to fetch {url}: status {}",
+            response.status
+        );
+        let len: u64 = response
+            .headers
+            .get("content-length")
+            .and_then(|len| len.parse().ok())
+            .with_context(|| format!("{url} doesn't tell its size"))?;
+        ensure!(
+            first.len() as u64 == BLOCK_SIZE.min(len),
+            "{url} ended early"
+        );
+        let mut blocks = BTreeMap::new();
+        blocks.insert(0, first);
+        Ok(Self {
+            url: url.to_string(),
+            len,
+            pos: 0,
+            blocks,
+            scratch,
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    /// the bytes downloaded so far
+    pub fn fetched(&self) -> u64 {
+        self.blocks.values().map(|block| block.len() as u64).sum()
+    }
+
+    fn block(&mut self, start: u64) -> Result<&[u8]> {
+        if !self.blocks.contains_key(&start) {
+            let path = self.scratch.path().join("range");
+            let (block, _) = fetch_range(&self.url, &path, start, BLOCK_SIZE)?;
+            ensure!(
+                block.len() as u64 == BLOCK_SIZE.min(self.len - start),
+                "{} ended early",
+                self.url
+            );
+            self.blocks.insert(start, block);
+        }
+        Ok(&self.blocks[&start])
+    }
+}
+
+impl Read for RemoteFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let start = self.pos - self.pos % BLOCK_SIZE;
+        let skip = (self.pos - start) as usize;
+        let block = self.block(start).map_err(std::io::Error::other)?;
+        let n = buf.len().min(block.len() - skip);
+        buf[..n].copy_from_slice(&block[skip..skip + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for RemoteFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = pos.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before the start")
+        })?;
+        Ok(self.pos)
+    }
+}
@@ -1,11 +1,113 @@
 use crate::defs::{KSU_MOUNT_SOURCE, TEMP_DIR};
 use crate::module::{handle_updated_modules, prune_modules};
 use crate::{assets, defs, ksucalls, restorecon, utils};
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use log::{info, warn};
 use rustix::fs::{mount, MountFlags};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_BOOT_WATCHDOG_THRESHOLD: u32 = 3;
+
+// The storage each stage may touch on FBE devices:
+//   post-fs-data, post-mount, service: device encrypted (DE) storage only, e.g. /data/adb
+//   boot-completed: DE only, with direct boot it may come before the user unlocked the device
+//   post-unlock: DE and credential encrypted (CE) storage, e.g. /data/data and /data/user/<id>
+//   on-shutdown: DE, CE only if the device was unlocked in this boot
+
+// the stages which must have run in this boot before a stage, init triggers them in this order
+const STAGE_PREREQUISITES: &[(&str, &[&str])] = &[
+    ("service", &["post-fs-data"]),
+    ("boot-completed", &["post-fs-data", "service"]),
+    ("post-unlock", &["post-fs-data"]),
+];
+
+fn ran_stages() -> Vec<String> {
+    std::fs::read_to_string(defs::BOOT_STAGES_FILE)
+        .map(|content| content.lines().map(ToString::to_string).collect())
+        .unwrap_or_default()
+}
+
+fn record_stage(stage: &str) {
+    let mut stages = ran_stages();
+    if stages.iter().any(|s| s == stage) {
+        return;
+    }
+    stages.push(stage.to_string());
+    if let Err(e) = std::fs::write(defs::BOOT_STAGES_FILE, stages.join("\n")) {
+        warn!("Failed to record boot stage {stage}: {e}");
+    }
+}
+
+// a stage triggered before the ones it depends on is a broken init or kernel integration,
+// the modules would see a half set up system; `strict` refuses to run it at all
+fn check_stage_order(stage: &str, strict: bool) -> Result<()> {
+    let Some((_, prerequisites)) = STAGE_PREREQUISITES.iter().find(|(s, _)| *s == stage) else {
+        return Ok(());
+    };
+    // post-fs-data stops early on purpose then, and the later stages don't run any script either
+    if utils::has_magisk() || utils::is_safe_mode() {
+        return Ok(());
+    }
+    let ran = ran_stages();
+    let missing: Vec<&str> = prerequisites
+        .iter()
+        .copied()
+        .filter(|prerequisite| !ran.iter().any(|s| s == prerequisite))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    if strict {
+        bail!(
+            "{stage} triggered before {}, refuse to run it",
+            missing.join(", ")
+        );
+    }
+    warn!(
+        "BOOT STAGE OUT OF ORDER: {stage} triggered before {}, modules may misbehave",
+        missing.join(", ")
+    );
+    Ok(())
+}
+
+fn read_number(path: &str) -> Option<u32> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// boots which may fail in a row before all modules are disabled, 0 turns the watchdog off
+pub fn boot_watchdog_threshold() -> u32 {
+    read_number(defs::BOOT_WATCHDOG_THRESHOLD_FILE).unwrap_or(DEFAULT_BOOT_WATCHDOG_THRESHOLD)
+}
+
+pub fn boot_attempts() -> u32 {
+    read_number(defs::BOOT_ATTEMPTS_FILE).unwrap_or(0)
+}
 
+// counts this boot as an attempt, true if too many boots before it didn't complete
+fn boot_watchdog_triggered() -> bool {
+    let attempts = boot_attempts() + 1;
+    let threshold = boot_watchdog_threshold();
+    if threshold != 0 && attempts > threshold {
+        // start over, so the modules the user enables again get the same chances
+        let _ = std::fs::remove_file(defs::BOOT_ATTEMPTS_FILE);
+        warn!("{} boots in a row didn't complete", attempts - 1);
+        return true;
+    }
+    if let Err(e) = std::fs::write(defs::BOOT_ATTEMPTS_FILE, attempts.to_string()) {
+        warn!("Failed to record boot attempt: {e}");
+    }
+    false
+}
+
+// Only device encrypted storage is available at this stage on FBE devices. What it touches:
+//   /data/adb/ksu: binaries, boot logs, boot watchdog state and the app profiles
+//   /data/adb/modules, /data/adb/modules_update: module updates, sepolicy.rule, system.prop,
+//     the post-fs-data scripts and the files which are magic mounted
+//   /data/adb/post-fs-data.d: the common scripts
+//   TEMP_DIR: a tmpfs mounted here
+// Anything in CE storage belongs in `on_post_unlock`.
 pub fn on_post_data_fs() -> Result<()> {
     ksucalls::report_post_fs_data();
 
@@ -21,6 +123,13 @@ pub fn on_post_data_fs() -> Result<()> {
         return Ok(());
     }
 
+    if boot_watchdog_triggered() {
+        warn!("boot watchdog: safe recovery triggered, disable all modules!");
+        if let Err(e) = crate::module::disable_all_modules() {
+            warn!("disable all modules failed: {}", e);
+        }
+    }
+
     let safe_mode = utils::is_safe_mode();
 
     if safe_mode {
@@ -54,6 +163,10 @@ pub fn on_post_data_fs() -> Result<()> {
         warn!("handle updated modules failed: {}", e);
     }
 
+    if let Err(e) = crate::module::check_enabled_conflicts() {
+        warn!("check module conflicts failed: {}", e);
+    }
+
     if let Err(e) = restorecon::restorecon() {
         warn!("restorecon failed: {}", e);
     }
@@ -90,6 +203,9 @@ pub fn on_post_data_fs() -> Result<()> {
 
     run_stage("post-mount", true);
 
+    // only once it completed, BOOT_STAGES_FILE lives in TEMP_DIR which is mounted above
+    record_stage("post-fs-data");
+
     Ok(())
 }
 
@@ -103,16 +219,62 @@ pub fn mount_modules_systemlessly() -> Result<()> {
     Ok(())
 }
 
-fn run_stage(stage: &str, block: bool) {
-    utils::umask(0);
+#[cfg(target_os = "android")]
+pub fn plan_module_now(module_dir: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+    crate::magic_mount::plan_module_now(module_dir)
+}
+
+#[cfg(not(target_os = "android"))]
+pub fn plan_module_now(_module_dir: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+    Ok(Vec::new())
+}
+
+#[cfg(target_os = "android")]
+pub fn mount_module_now(plan: &[(PathBuf, PathBuf)]) -> Result<Vec<PathBuf>> {
+    crate::magic_mount::mount_module_now(plan)
+}
 
+#[cfg(not(target_os = "android"))]
+pub fn mount_module_now(_plan: &[(PathBuf, PathBuf)]) -> Result<Vec<PathBuf>> {
+    Ok(Vec::new())
+}
+
+#[cfg(target_os = "android")]
+pub fn unmount_modules_in_private_ns() -> Result<()> {
+    crate::magic_mount::unmount_modules_in_private_ns()
+}
+
+#[cfg(not(target_os = "android"))]
+pub fn unmount_modules_in_private_ns() -> Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "android")]
+pub fn unmount_module_now(paths: &[PathBuf]) {
+    crate::magic_mount::unmount_all(paths);
+}
+
+#[cfg(not(target_os = "android"))]
+pub fn unmount_module_now(_paths: &[PathBuf]) {}
+
+fn can_run_stage(stage: &str) -> bool {
     if utils::has_magisk() {
         warn!("Magisk detected, skip {stage}");
-        return;
+        return false;
     }
 
     if crate::utils::is_safe_mode() {
         warn!("safe mode, skip {stage} scripts");
+        return false;
+    }
+
+    true
+}
+
+fn run_stage(stage: &str, block: bool) {
+    utils::umask(0);
+
+    if !can_run_stage(stage) {
         return;
     }
 
@@ -124,22 +286,75 @@ fn run_stage(stage: &str, block: bool) {
     }
 }
 
-pub fn on_services() -> Result<()> {
+pub fn on_services(strict: bool) -> Result<()> {
     info!("on_services triggered!");
+    check_stage_order("service", strict)?;
     run_stage("service", false);
+    record_stage("service");
 
     Ok(())
 }
 
-pub fn on_boot_completed() -> Result<()> {
+pub fn on_boot_completed(strict: bool) -> Result<()> {
     ksucalls::report_boot_complete();
     info!("on_boot_completed triggered!");
+    check_stage_order("boot-completed", strict)?;
+
+    // the boot completed, the boot watchdog counts from zero again
+    let _ = std::fs::remove_file(defs::BOOT_ATTEMPTS_FILE);
+
+    // it may be triggered more than once in a boot, e.g. when switching users
+    if Path::new(defs::BOOT_COMPLETED_MARKER).exists() {
+        info!("boot-completed already ran in this boot, only rerun the modules which opt in");
+        utils::umask(0);
+        if can_run_stage("boot-completed") {
+            if let Err(e) = crate::module::exec_boot_completed_rerun_scripts(false) {
+                warn!("Failed to exec boot-completed scripts: {e}");
+            }
+        }
+        return Ok(());
+    }
+
+    if let Err(e) = utils::ensure_file_exists(defs::BOOT_COMPLETED_MARKER) {
+        warn!("Failed to create boot-completed marker: {e}");
+    }
 
     run_stage("boot-completed", false);
+    record_stage("boot-completed");
 
     Ok(())
 }
 
+// Triggered once the credential encrypted storage of user 0 is unlocked, module scripts which
+// need to access app data (/data/data, /data/user/0) should use the post-unlock stage.
+pub fn on_post_unlock(strict: bool) -> Result<()> {
+    info!("on_post_unlock triggered!");
+    check_stage_order("post-unlock", strict)?;
+
+    if !utils::is_ce_storage_available() {
+        warn!("credential encrypted storage is still locked, skip post-unlock");
+        return Ok(());
+    }
+
+    run_stage("post-unlock", false);
+    record_stage("post-unlock");
+
+    Ok(())
+}
+
+// Triggered when the device starts to shut down or reboot, init waits for us so the
+// scripts only get a short time.
+pub fn on_shutdown() -> Result<()> {
+    info!("on_shutdown triggered!");
+    utils::umask(0);
+
+    if !can_run_stage("on-shutdown") {
+        return Ok(());
+    }
+
+    crate::module::exec_shutdown_scripts(SHUTDOWN_TIMEOUT)
+}
+
 #[cfg(unix)]
 fn catch_bootlog(logname: &str, command: Vec<&str>) -> Result<()> {
     use std::os::unix::process::CommandExt;
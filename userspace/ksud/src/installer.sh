@@ -315,6 +315,32 @@ handle_partition() {
     fi
 }
 
+# a multi-arch module sets archTrees=true in module.prop and ships arm64/,
+# x86_64/, ... and optionally common/, keep common/ and the tree of the
+# device arch. ksud passes the tree and the arch trees in KSU_ARCH_TREE and
+# KSU_ARCH_TREES only for such modules; without the device tree it's a no-op
+merge_arch_trees() {
+    [ -n "$KSU_ARCH_TREE" ] && [ -d "$MODPATH/$KSU_ARCH_TREE" ] || return
+
+    if [ -d "$MODPATH/common" ]; then
+        cp -af "$MODPATH/common/." "$MODPATH/" && rm -rf "$MODPATH/common"
+    fi
+    cp -af "$MODPATH/$KSU_ARCH_TREE/." "$MODPATH/"
+    for TREE in $KSU_ARCH_TREES; do
+      rm -rf "$MODPATH/$TREE"
+    done
+    ui_print "- Merged files for $KSU_ARCH_TREE"
+}
+
+# ksud passes the hash manifest of a zip which ships one in KSU_HASH_MANIFEST,
+# check what unzip extracted to $MODPATH before any code of the module runs.
+# --partial only checks the files which are there
+check_hashes() {
+    [ -n "$KSU_HASH_MANIFEST" ] || return 0
+    /data/adb/ksud module check-hashes "$KSU_HASH_MANIFEST" "$MODPATH" "$@" \
+      || abort "! Module files don't match the hash manifest"
+}
+
 # Require OUTFD, ZIPFILE to be set
 install_module() {
   rm -rf $TMPDIR
@@ -349,6 +375,7 @@ install_module() {
   mkdir -p $MODPATH
 
   if is_legacy_script; then
+    [ -n "$KSU_HASH_MANIFEST" ] && abort "! install.sh modules can't be checked against a hash manifest"
     unzip -oj "$ZIPFILE" module.prop install.sh uninstall.sh 'common/*' -d $TMPDIR >&2
 
     # Load install script
@@ -372,10 +399,16 @@ install_module() {
     print_title "Powered by KernelSU"
 
     unzip -o "$ZIPFILE" customize.sh -d $MODPATH >&2
+    check_hashes --partial
 
-    if ! grep -q '^SKIPUNZIP=1$' $MODPATH/customize.sh 2>/dev/null; then
+    if grep -q '^SKIPUNZIP=1$' $MODPATH/customize.sh 2>/dev/null; then
+      # customize.sh extracts the files itself, nothing runs between that and its own code
+      [ -n "$KSU_HASH_MANIFEST" ] && abort "! SKIPUNZIP modules can't be checked against a hash manifest"
+    else
       ui_print "- Extracting module files"
       unzip -o "$ZIPFILE" -x 'META-INF/*' -d $MODPATH >&2
+      check_hashes
+      merge_arch_trees
 
       # Default permissions
       set_perm_recursive $MODPATH 0 0 0755 0644
@@ -387,6 +420,10 @@ install_module() {
 
     # Load customization script
     [ -f $MODPATH/customize.sh ] && . $MODPATH/customize.sh
+
+    # with SKIPUNZIP=1 customize.sh extracts the files itself, merge
+    # whatever arch trees it left in place
+    grep -q '^SKIPUNZIP=1$' $MODPATH/customize.sh 2>/dev/null && merge_arch_trees
   fi
 
   handle_partition vendor true
@@ -436,7 +473,7 @@ install_module() {
 [ -z $BOOTMODE ] && BOOTMODE=false
 
 NVBASE=/data/adb
-TMPDIR=/dev/tmp
+TMPDIR=${KSU_INSTALL_TMPDIR:-/dev/tmp}
 POSTFSDATAD=$NVBASE/post-fs-data.d
 SERVICED=$NVBASE/service.d
 
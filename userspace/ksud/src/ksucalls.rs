@@ -2,9 +2,113 @@ const EVENT_POST_FS_DATA: u64 = 1;
 const EVENT_BOOT_COMPLETED: u64 = 2;
 const EVENT_MODULE_MOUNTED: u64 = 3;
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const KERNEL_SU_OPTION: u32 = 0xDEAD_BEEF;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const CMD_GET_VERSION: u64 = 2;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const CMD_GET_ALLOW_LIST: u64 = 5;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const CMD_REPORT_EVENT: u64 = 7;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const CMD_GET_CONFIG_FLAGS: u64 = 14;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const CMD_GET_MANAGER_UID: u64 = 15;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const CMD_GET_ALLOW_LIST_LIMIT: u64 = 16;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const CMD_REVOKE_ROOT: u64 = 17;
+
+// the allow list buffer for kernels which can't tell their limit, they return at most 128 uids
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const LEGACY_ALLOW_LIST_LIMIT: usize = 128;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const MAX_RETRIES: u32 = 3;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_millis(10);
+
+pub const CONFIG_DEBUG: u32 = 1 << 0;
+pub const CONFIG_CMDLINE: u32 = 1 << 1;
+pub const CONFIG_KPROBES: u32 = 1 << 2;
+pub const CONFIG_LKM: u32 = 1 << 3;
+pub const CONFIG_UMOUNT: u32 = 1 << 4;
+
+/// Retry `f` when it fails with a transient error.
+/// EINTR is retried at once and EAGAIN/EBUSY with a short backoff, both are bounded;
+/// any other error means the call is not supported and is returned directly.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn retry_transient<T>(mut f: impl FnMut() -> std::io::Result<T>) -> std::io::Result<T> {
+    let mut backoff = RETRY_BACKOFF;
+    let mut retries = 0;
+    loop {
+        let err = match f() {
+            Ok(v) => return Ok(v),
+            Err(err) => err,
+        };
+        if retries >= MAX_RETRIES {
+            return Err(err);
+        }
+        retries += 1;
+        match err.raw_os_error() {
+            Some(libc::EINTR) => {}
+            Some(libc::EAGAIN | libc::EBUSY) => {
+                std::thread::sleep(backoff);
+                backoff *= 2;
+            }
+            _ => return Err(err),
+        }
+        log::warn!("ksucall failed: {err}, retry {retries}/{MAX_RETRIES}");
+    }
+}
+
+// The kernel replies KERNEL_SU_OPTION to `result` for the commands it handled,
+// prctl itself usually fails with EINVAL even if the command succeeded.
+// errno is only meaningful when prctl failed, a kernel which didn't handle the command
+// and left prctl succeed is reported as unsupported.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn ksuctl(cmd: u64, arg3: *mut libc::c_void, arg4: *mut libc::c_void) -> std::io::Result<()> {
+    let mut result = 0u32;
+    let ret = unsafe {
+        libc::prctl(
+            KERNEL_SU_OPTION as libc::c_int,
+            cmd,
+            arg3,
+            arg4,
+            std::ptr::addr_of_mut!(result),
+        )
+    };
+    if result == KERNEL_SU_OPTION {
+        Ok(())
+    } else if ret == -1 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("kernel didn't reply to ksucall {cmd}"),
+        ))
+    }
+}
+
 #[cfg(any(target_os = "linux", target_os = "android"))]
 pub fn get_version() -> i32 {
-    rustix::process::ksu_get_version()
+    // the kernel doesn't reply for this command, a non-zero version means success
+    let mut version = 0i32;
+    let result = retry_transient(|| {
+        let replied = ksuctl(
+            CMD_GET_VERSION,
+            std::ptr::addr_of_mut!(version).cast(),
+            std::ptr::null_mut(),
+        );
+        if version > 0 {
+            Ok(version)
+        } else {
+            Err(replied.err().unwrap_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::Unsupported, "kernel has no version")
+            }))
+        }
+    });
+    result.unwrap_or(0)
 }
 
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
@@ -12,9 +116,111 @@ pub fn get_version() -> i32 {
     0
 }
 
+/// build-time options of the kernel, None if the kernel doesn't support the query
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn get_config_flags() -> Option<u32> {
+    let mut flags = 0u32;
+    retry_transient(|| {
+        ksuctl(
+            CMD_GET_CONFIG_FLAGS,
+            std::ptr::addr_of_mut!(flags).cast(),
+            std::ptr::null_mut(),
+        )
+    })
+    .ok()
+    .map(|()| flags)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn get_config_flags() -> Option<u32> {
+    None
+}
+
+/// uid of the manager the kernel recognized, Some(None) if it found none,
+/// None if the kernel doesn't support the query
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn get_manager_uid() -> Option<Option<u32>> {
+    let mut uid = 0u32;
+    retry_transient(|| {
+        ksuctl(
+            CMD_GET_MANAGER_UID,
+            std::ptr::addr_of_mut!(uid).cast(),
+            std::ptr::null_mut(),
+        )
+    })
+    .ok()?;
+    // KSU_INVALID_UID
+    Some((uid != u32::MAX).then_some(uid))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn get_manager_uid() -> Option<Option<u32>> {
+    None
+}
+
+/// the most uids the kernel grants root, None if the kernel doesn't support the query
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn get_allow_list_limit() -> Option<usize> {
+    let mut limit = 0u32;
+    retry_transient(|| {
+        ksuctl(
+            CMD_GET_ALLOW_LIST_LIMIT,
+            std::ptr::addr_of_mut!(limit).cast(),
+            std::ptr::null_mut(),
+        )
+    })
+    .ok()
+    .map(|()| limit as usize)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn get_allow_list_limit() -> Option<usize> {
+    None
+}
+
+/// uids granted root by the kernel, None if the kernel doesn't reply
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn get_allow_list() -> Option<Vec<u32>> {
+    let limit = get_allow_list_limit().unwrap_or(LEGACY_ALLOW_LIST_LIMIT);
+    let mut uids = vec![0u32; limit];
+    let mut len = 0u32;
+    retry_transient(|| {
+        ksuctl(
+            CMD_GET_ALLOW_LIST,
+            uids.as_mut_ptr().cast(),
+            std::ptr::addr_of_mut!(len).cast(),
+        )
+    })
+    .ok()?;
+    uids.truncate(len as usize);
+    Some(uids)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn get_allow_list() -> Option<Vec<u32>> {
+    None
+}
+
+/// Revoke root of `uid`, the kernel gives it the default non-root profile like the manager
+/// does. Kernels without the command can't be asked by root, only the manager can revoke there.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn revoke_root(uid: u32) -> std::io::Result<()> {
+    retry_transient(|| ksuctl(CMD_REVOKE_ROOT, uid as *mut _, std::ptr::null_mut()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn revoke_root(_uid: u32) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+// older kernels don't reply to events, they are handled all the same
 #[cfg(any(target_os = "linux", target_os = "android"))]
 fn report_event(event: u64) {
-    rustix::process::ksu_report_event(event)
+    if let Err(e) =
+        retry_transient(|| ksuctl(CMD_REPORT_EVENT, event as *mut _, std::ptr::null_mut()))
+    {
+        log::debug!("report event {event}: {e}");
+    }
 }
 
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
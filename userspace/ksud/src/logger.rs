@@ -0,0 +1,140 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Mutex;
+
+use crate::defs;
+
+// the most records kept, older ones are dropped
+const RECENT_LOGS_CAPACITY: usize = 256;
+// the file is only trimmed to the capacity once it grows past this, most runs just append
+const RECENT_LOGS_FILE_LIMIT: u64 = 64 * 1024;
+
+static RECENT_LOGS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+// forwards to the platform logger and keeps the last records; the levels which are off never
+// get here, so they aren't formatted either
+struct RecentLogger {
+    inner: Box<dyn Log>,
+}
+
+impl Log for RecentLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.log(record);
+        let line = format!(
+            "{} {:<5} {}: {}",
+            chrono::Local::now().format("%m-%d %H:%M:%S%.3f"),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        if let Ok(mut logs) = RECENT_LOGS.lock() {
+            if logs.len() == RECENT_LOGS_CAPACITY {
+                logs.pop_front();
+            }
+            logs.push_back(line);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Install the logger, records are kept in memory until [`save`] merges them into the file.
+pub fn init() {
+    #[cfg(target_os = "android")]
+    let inner: Box<dyn Log> = Box::new(android_logger::AndroidLogger::new(
+        android_logger::Config::default()
+            .with_max_level(LevelFilter::Trace) // limit log level
+            .with_tag("KernelSU"), // logs will show under mytag tag
+    ));
+
+    #[cfg(not(target_os = "android"))]
+    let inner: Box<dyn Log> = Box::new(env_logger::Builder::from_default_env().build());
+
+    if log::set_boxed_logger(Box::new(RecentLogger { inner })).is_ok() {
+        log::set_max_level(LevelFilter::Trace);
+    }
+}
+
+/// When not verbose, only info and more severe records are logged.
+pub fn set_verbose(verbose: bool) {
+    log::set_max_level(if verbose {
+        LevelFilter::Trace
+    } else {
+        LevelFilter::Info
+    });
+}
+
+// the records saved by the earlier commands in this boot followed by the given ones
+fn merged_logs(own: Vec<String>) -> Vec<String> {
+    let mut logs: Vec<String> = std::fs::read_to_string(defs::RECENT_LOGS_FILE)
+        .map(|content| content.lines().map(ToString::to_string).collect())
+        .unwrap_or_default();
+    logs.extend(own);
+    let skip = logs.len().saturating_sub(RECENT_LOGS_CAPACITY);
+    logs.split_off(skip)
+}
+
+// concurrent runs append and trim one after another
+#[cfg(unix)]
+fn lock(file: &File) {
+    use std::os::fd::AsRawFd;
+    unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) };
+}
+
+#[cfg(not(unix))]
+fn lock(_file: &File) {}
+
+fn append(own: &[String]) -> std::io::Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(defs::RECENT_LOGS_FILE)?;
+    lock(&file);
+    let mut content = own.join("\n");
+    content.push('\n');
+    file.write_all(content.as_bytes())?;
+    if file.metadata()?.len() <= RECENT_LOGS_FILE_LIMIT {
+        return Ok(());
+    }
+    // trimmed in place, a run waiting for the lock would append to a renamed away file
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut content)?;
+    let lines: Vec<&str> = content.lines().collect();
+    let mut kept = lines[lines.len().saturating_sub(RECENT_LOGS_CAPACITY)..].join("\n");
+    kept.push('\n');
+    file.set_len(0)?;
+    file.write_all(kept.as_bytes())
+}
+
+/// Append the records of this process to [`defs::RECENT_LOGS_FILE`] for `debug recent-logs`,
+/// must run before every exit or exec since the records are lost with the process.
+pub fn save() {
+    let own: Vec<String> = RECENT_LOGS
+        .lock()
+        .map(|mut logs| logs.drain(..).collect())
+        .unwrap_or_default();
+    if !own.is_empty() {
+        let _ = append(&own);
+    }
+}
+
+pub fn print_recent_logs() -> anyhow::Result<()> {
+    let own = RECENT_LOGS
+        .lock()
+        .map(|logs| logs.iter().cloned().collect())
+        .unwrap_or_default();
+    for line in merged_logs(own) {
+        println!("{line}");
+    }
+    Ok(())
+}
@@ -1,10 +1,11 @@
 use crate::defs::{
-    DISABLE_FILE_NAME, KSU_MOUNT_SOURCE, MAGIC_MOUNT_WORK_DIR, MODULE_DIR, SKIP_MOUNT_FILE_NAME,
+    DISABLE_FILE_NAME, KSU_MOUNT_SOURCE, MAGIC_MOUNT_WORK_DIR, MODULE_DIR, REPLACE_DIR_XATTR,
+    SKIP_MOUNT_FILE_NAME,
 };
 use crate::magic_mount::NodeFileType::{Directory, RegularFile, Symlink, Whiteout};
 use crate::restorecon::{lgetfilecon, lsetfilecon};
 use crate::utils::ensure_dir_exists;
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use extattr::lgetxattr;
 use rustix::fs::{
     bind_mount, chmod, chown, mount, move_mount, unmount, Gid, MetadataExt, Mode, MountFlags,
@@ -20,8 +21,6 @@ use std::fs::{create_dir, create_dir_all, read_dir, read_link, DirEntry, FileTyp
 use std::os::unix::fs::{symlink, FileTypeExt};
 use std::path::{Path, PathBuf};
 
-const REPLACE_DIR_XATTR: &str = "trusted.overlay.opaque";
-
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
 enum NodeFileType {
     RegularFile,
@@ -122,7 +121,7 @@ impl Node {
     }
 }
 
-fn collect_module_files() -> Result<Option<Node>> {
+fn collect_module_files(include_disabled: bool) -> Result<Option<Node>> {
     let mut root = Node::new_root("");
     let mut system = Node::new_root("system");
     let module_root = Path::new(MODULE_DIR);
@@ -132,7 +131,7 @@ fn collect_module_files() -> Result<Option<Node>> {
             continue;
         }
 
-        if entry.path().join(DISABLE_FILE_NAME).exists()
+        if (!include_disabled && entry.path().join(DISABLE_FILE_NAME).exists())
             || entry.path().join(SKIP_MOUNT_FILE_NAME).exists()
         {
             continue;
@@ -233,6 +232,42 @@ fn mount_mirror<P: AsRef<Path>, WP: AsRef<Path>>(
     Ok(())
 }
 
+// a tmpfs is needed if the dir is replaced, or a child can't be mounted onto an existing file,
+// children which need a tmpfs on a root dir are marked as skipped.
+fn should_create_tmpfs(path: &Path, current: &mut Node, has_tmpfs: bool) -> bool {
+    let mut create_tmpfs = !has_tmpfs && current.replace && current.module_path.is_some();
+    if !has_tmpfs && !create_tmpfs {
+        for it in &mut current.children {
+            let (name, node) = it;
+            let real_path = path.join(name);
+            let need = match node.file_type {
+                Symlink => true,
+                Whiteout => real_path.exists(),
+                _ => {
+                    if let Ok(metadata) = real_path.metadata() {
+                        let file_type =
+                            NodeFileType::from_file_type(metadata.file_type()).unwrap_or(Whiteout);
+                        file_type != node.file_type || file_type == Symlink
+                    } else {
+                        // real path not exists
+                        true
+                    }
+                }
+            };
+            if need {
+                if current.module_path.is_none() {
+                    log::error!("cannot create tmpfs on {}, ignore: {name}", path.display());
+                    node.skip = true;
+                    continue;
+                }
+                create_tmpfs = true;
+                break;
+            }
+        }
+    }
+    create_tmpfs
+}
+
 fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
     path: P,
     work_dir_path: WP,
@@ -274,40 +309,7 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
             }
         }
         Directory => {
-            let mut create_tmpfs = !has_tmpfs && current.replace && current.module_path.is_some();
-            if !has_tmpfs && !create_tmpfs {
-                for it in &mut current.children {
-                    let (name, node) = it;
-                    let real_path = path.join(name);
-                    let need = match node.file_type {
-                        Symlink => true,
-                        Whiteout => real_path.exists(),
-                        _ => {
-                            if let Ok(metadata) = real_path.metadata() {
-                                let file_type = NodeFileType::from_file_type(metadata.file_type())
-                                    .unwrap_or(Whiteout);
-                                file_type != node.file_type || file_type == Symlink
-                            } else {
-                                // real path not exists
-                                true
-                            }
-                        }
-                    };
-                    if need {
-                        if current.module_path.is_none() {
-                            log::error!(
-                                "cannot create tmpfs on {}, ignore: {name}",
-                                path.display()
-                            );
-                            node.skip = true;
-                            continue;
-                        }
-                        create_tmpfs = true;
-                        break;
-                    }
-                }
-            }
-
+            let create_tmpfs = should_create_tmpfs(&path, &mut current, has_tmpfs);
             let has_tmpfs = has_tmpfs || create_tmpfs;
 
             if has_tmpfs {
@@ -415,7 +417,7 @@ fn do_magic_mount<P: AsRef<Path>, WP: AsRef<Path>>(
 }
 
 pub fn magic_mount() -> Result<()> {
-    if let Some(root) = collect_module_files()? {
+    if let Some(root) = collect_module_files(false)? {
         log::debug!("collected: {:#?}", root);
         let tmp_dir = PathBuf::from(MAGIC_MOUNT_WORK_DIR);
         ensure_dir_exists(&tmp_dir)?;
@@ -432,3 +434,191 @@ pub fn magic_mount() -> Result<()> {
         Ok(())
     }
 }
+
+pub struct MountPlanEntry {
+    pub module: String,
+    pub source: String,
+    pub target: String,
+    pub fstype: &'static str,
+    pub options: String,
+    /// the module is disabled, it would be mounted like this once enabled
+    pub disabled: bool,
+}
+
+// id of the module which owns the file, module files live in /data/adb/modules/<id>/system
+fn module_id_of(module_path: &Path) -> String {
+    module_path
+        .strip_prefix(MODULE_DIR)
+        .ok()
+        .and_then(|p| p.components().next())
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+// walks the tree like do_magic_mount without touching anything, the mirrors of the untouched
+// entries in a tmpfs are not part of the plan.
+fn plan_magic_mount(
+    path: &Path,
+    mut current: Node,
+    has_tmpfs: bool,
+    plan: &mut Vec<MountPlanEntry>,
+) {
+    let path = path.join(&current.name);
+    let module_path = current.module_path.clone().unwrap_or_default();
+    let mut push = |fstype: &'static str, source: String, options: &str| {
+        let module = module_id_of(&module_path);
+        let disabled = !module.is_empty()
+            && Path::new(MODULE_DIR)
+                .join(&module)
+                .join(DISABLE_FILE_NAME)
+                .exists();
+        plan.push(MountPlanEntry {
+            module,
+            source,
+            target: path.display().to_string(),
+            fstype,
+            options: options.to_string(),
+            disabled,
+        });
+    };
+    match current.file_type {
+        RegularFile => {
+            if current.module_path.is_some() {
+                push("bind", module_path.display().to_string(), "");
+            }
+        }
+        Symlink => {
+            if let Ok(link) = read_link(&module_path) {
+                push("symlink", link.display().to_string(), "");
+            }
+        }
+        Whiteout => {
+            if has_tmpfs && path.exists() {
+                push("whiteout", String::new(), "");
+            }
+        }
+        Directory => {
+            let create_tmpfs = should_create_tmpfs(&path, &mut current, has_tmpfs);
+            if create_tmpfs {
+                let options = if current.replace { "replace" } else { "" };
+                push("tmpfs", KSU_MOUNT_SOURCE.to_string(), options);
+            }
+            let mut children: Vec<Node> = current
+                .children
+                .into_values()
+                .filter(|node| !node.skip)
+                .collect();
+            children.sort_by(|a, b| a.name.cmp(&b.name));
+            for node in children {
+                plan_magic_mount(&path, node, has_tmpfs || create_tmpfs, plan);
+            }
+        }
+    }
+}
+
+/// What magic mount would do on this boot, in order, without mounting anything.
+/// With `include_disabled` the disabled modules are planned as if they were enabled.
+pub fn mount_plan(include_disabled: bool) -> Result<Vec<MountPlanEntry>> {
+    let mut plan = Vec::new();
+    if let Some(root) = collect_module_files(include_disabled)? {
+        plan_magic_mount(Path::new("/"), root, false, &mut plan);
+    }
+    Ok(plan)
+}
+
+// only a file replacing an existing file can be bind mounted as is, anything else needs a
+// tmpfs over its parent which only magic mount sets up on boot
+fn plan_module_files_now(
+    src: &Path,
+    target: &Path,
+    plan: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<()> {
+    for entry in src.read_dir()?.flatten() {
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        let target_path = target.join(entry.file_name());
+        if file_type.is_dir() && target_path.is_dir() {
+            let replace = lgetxattr(&path, REPLACE_DIR_XATTR)
+                .is_ok_and(|v| String::from_utf8_lossy(&v) == "y");
+            ensure!(
+                !replace,
+                "{} replaces a whole directory",
+                target_path.display()
+            );
+            plan_module_files_now(&path, &target_path, plan)?;
+        } else if file_type.is_file() && target_path.is_file() {
+            plan.push((path, target_path));
+        } else if target_path.symlink_metadata().is_ok() {
+            bail!(
+                "{} changes the type of an existing file",
+                target_path.display()
+            );
+        } else {
+            bail!("{} is a new path", target_path.display());
+        }
+    }
+    Ok(())
+}
+
+/// What mounting the files of a single module without reboot would bind mount, source and
+/// target. Fails if the module needs a tmpfs, i.e. adds new paths, removes files or replaces
+/// directories, those modules can only be mounted by magic mount on next boot.
+pub fn plan_module_now(module_dir: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mod_system = module_dir.join("system");
+    let mut plan = Vec::new();
+    if mod_system.is_dir() && !module_dir.join(SKIP_MOUNT_FILE_NAME).exists() {
+        plan_module_files_now(&mod_system, Path::new("/system"), &mut plan)?;
+    }
+    Ok(plan)
+}
+
+/// Bind mount what [`plan_module_now`] planned, returns the mounted paths, nothing is left
+/// mounted if it fails.
+pub fn mount_module_now(plan: &[(PathBuf, PathBuf)]) -> Result<Vec<PathBuf>> {
+    let mut mounted = Vec::new();
+    for (src, target) in plan {
+        log::debug!(
+            "mount module file {} -> {}",
+            src.display(),
+            target.display()
+        );
+        if let Err(e) = bind_mount(src, target) {
+            unmount_all(&mounted);
+            return Err(e).with_context(|| format!("mount {}", target.display()));
+        }
+        mounted.push(target.clone());
+    }
+    Ok(mounted)
+}
+
+/// Unmount everything magic mount mounted to see the stock partitions, only call it in a mount
+/// namespace of our own, or the modules are gone for the whole system.
+pub fn unmount_modules_in_private_ns() -> Result<()> {
+    // never let the unmounts propagate to the other namespaces
+    mount_change(
+        "/",
+        MountPropagationFlags::PRIVATE | MountPropagationFlags::REC,
+    )
+    .context("make / private")?;
+    // module files are bind mounted from /data, whose root is the data partition itself
+    let modules_root = MODULE_DIR.trim_start_matches("/data");
+    let mounts = procfs::process::Process::myself()?.mountinfo()?;
+    for info in mounts.into_iter().rev() {
+        let is_module_mount = (info.fs_type == "tmpfs"
+            && info.mount_source.as_deref() == Some(KSU_MOUNT_SOURCE))
+            || info.root.starts_with(modules_root);
+        if is_module_mount {
+            // may be gone with its parent already
+            unmount(&info.mount_point, UnmountFlags::DETACH).ok();
+        }
+    }
+    Ok(())
+}
+
+pub fn unmount_all(paths: &[PathBuf]) {
+    for path in paths.iter().rev() {
+        if let Err(e) = unmount(path, UnmountFlags::DETACH) {
+            log::error!("failed to unmount {}: {}", path.display(), e);
+        }
+    }
+}
@@ -3,16 +3,22 @@ mod assets;
 mod cli;
 mod debug;
 mod defs;
+mod elf_arch;
+mod http;
 mod init_event;
 mod ksucalls;
+mod logger;
 #[cfg(target_os = "android")]
 mod magic_mount;
 mod module;
+mod policydb;
 mod profile;
 mod restorecon;
 mod sepolicy;
+mod status;
 mod su;
 mod utils;
+mod webui;
 
 fn main() -> anyhow::Result<()> {
     cli::run()
@@ -1,8 +1,8 @@
 #[allow(clippy::wildcard_imports)]
 use crate::utils::*;
 use crate::{
-    assets, defs, ksucalls,
-    restorecon::{restore_syscon, setsyscon},
+    apk_sign, assets, defs, ksucalls,
+    restorecon::{lsetfilecon, restore_syscon, setsyscon},
     sepolicy,
 };
 
@@ -14,20 +14,25 @@ use log::{info, warn};
 
 use std::fs::{copy, rename};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet},
     env::var as env_var,
     fs::{remove_dir_all, remove_file, set_permissions, File, Permissions},
-    io::Cursor,
+    io::{BufRead, BufReader, Cursor, IsTerminal, Read, Write},
     path::{Path, PathBuf},
-    process::Command,
-    str::FromStr,
+    process::{Command, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
-use zip_extensions::zip_extract_file_to_memory;
 
 use crate::defs::{MODULE_DIR, MODULE_UPDATE_DIR, UPDATE_FILE_NAME};
 #[cfg(unix)]
 use std::os::unix::{prelude::PermissionsExt, process::CommandExt};
 
+const SKIPPED_SEPOLICY_RULE: &str = "sepolicy.rule.skipped";
+const PERMISSIONS_FILE_NAME: &str = "permissions";
+const MODULE_ARCHES: &[&str] = &["arm64", "arm", "x86_64", "x86"];
+const COMMON_TREE: &str = "common";
+
 const INSTALLER_CONTENT: &str = include_str!("./installer.sh");
 const INSTALL_MODULE_SCRIPT: &str = concatcp!(
     INSTALLER_CONTENT,
@@ -38,28 +43,238 @@ const INSTALL_MODULE_SCRIPT: &str = concatcp!(
     "\n"
 );
 
-fn exec_install_script(module_file: &str) -> Result<()> {
+/// PATH of the module scripts, busybox applets are preferred over it in standalone mode
+pub fn get_script_path_env() -> String {
+    format!(
+        "{}:{}",
+        env_var("PATH").unwrap(),
+        defs::BINARY_DIR.trim_end_matches('/')
+    )
+}
+
+// copy the lines of `from` to `to` and to the log as they come
+fn tee_lines(
+    from: impl Read + Send + 'static,
+    mut to: impl Write + Send + 'static,
+    log: Arc<Mutex<File>>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        for line in BufReader::new(from)
+            .split(b'\n')
+            .map_while(std::io::Result::ok)
+        {
+            let _ = to.write_all(&line).and_then(|_| to.write_all(b"\n"));
+            let _ = to.flush();
+            if let Ok(mut log) = log.lock() {
+                let _ = log.write_all(&line).and_then(|_| log.write_all(b"\n"));
+            }
+        }
+    })
+}
+
+/// the output of customize.sh in the last install of module `id`
+fn install_log_file(id: &str) -> PathBuf {
+    Path::new(defs::MODULE_INSTALL_STATE_DIR).join(format!("{id}.log"))
+}
+
+/// the files module `id` provided to the overlay right after it was extracted,
+/// before customize.sh ran
+fn install_manifest_file(id: &str) -> PathBuf {
+    Path::new(defs::MODULE_INSTALL_STATE_DIR).join(format!("{id}.files"))
+}
+
+// the files, symlinks and whiteouts under system/ of the module, sorted, dirs are left out
+fn overlay_files(module_dir: &Path) -> Vec<String> {
+    let mut files: Vec<String> = jwalk::WalkDir::new(module_dir.join("system"))
+        .parallelism(jwalk::Parallelism::Serial)
+        .skip_hidden(false)
+        .into_iter()
+        .flatten()
+        .filter(|entry| !entry.file_type().is_dir())
+        .filter_map(|entry| {
+            let path = entry.path();
+            let relative = path.strip_prefix(module_dir).ok()?;
+            Some(relative.to_string_lossy().to_string())
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+/// sha256sum style list of the files in the zip, checked against what installer.sh extracts.
+/// It isn't signed, whoever can change the zip can change it too: it only catches files which
+/// differ from what the zip lists, e.g. a zip whose local headers disagree with its central
+/// directory, and files changed after the install.
+const HASH_MANIFEST: &str = "META-INF/ksu/manifest.sha256";
+
+/// the hash manifest of the last install of module `id`
+fn hash_manifest_file(id: &str) -> PathBuf {
+    Path::new(defs::MODULE_INSTALL_STATE_DIR).join(format!("{id}.sha256"))
+}
+
+/// the hash manifest of the install of module `id` in progress, it replaces the saved one
+/// once the install succeeded
+fn pending_hash_manifest_file(id: &str) -> PathBuf {
+    Path::new(defs::MODULE_INSTALL_STATE_DIR).join(format!("{id}.sha256.new"))
+}
+
+// `<sha256>  <path>` per line, the path is relative to the top of the zip
+fn parse_hash_manifest(content: &str) -> Result<Vec<(String, String)>> {
+    let mut entries = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (digest, path) = line
+            .split_once(' ')
+            .map(|(digest, path)| (digest, path.trim_start_matches([' ', '*'])))
+            .with_context(|| format!("{HASH_MANIFEST}:{}: expect <sha256>  <path>", i + 1))?;
+        ensure!(
+            digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()),
+            "{HASH_MANIFEST}:{}: bad sha256 {digest}",
+            i + 1
+        );
+        let relative = Path::new(path);
+        ensure!(
+            !path.is_empty()
+                && relative
+                    .components()
+                    .all(|c| matches!(c, std::path::Component::Normal(_))),
+            "{HASH_MANIFEST}:{}: bad path {path}",
+            i + 1
+        );
+        entries.push((digest.to_ascii_lowercase(), path.to_string()));
+    }
+    Ok(entries)
+}
+
+// the digest a hash manifest lists for `path`: the content of a file, the target of a symlink
+// as the zip stores it. None for anything else.
+fn manifest_digest(path: &Path) -> Result<Option<String>> {
+    let file_type = std::fs::symlink_metadata(path)?.file_type();
+    if file_type.is_symlink() {
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let target = std::fs::read_link(path)?;
+            return Ok(Some(sha256::digest(target.as_os_str().as_bytes())));
+        }
+    }
+    if file_type.is_file() {
+        return Ok(Some(sha256::try_digest(path)?));
+    }
+    Ok(None)
+}
+
+/// Check the files and symlinks under `dir` against the hash manifest `manifest`, installer.sh
+/// runs it on $MODPATH once unzip extracted it. Every file must be listed with the same sha256,
+/// and with `partial` unset every listed file must be there.
+pub fn check_hashes(manifest: &str, dir: &str, partial: bool) -> Result<()> {
+    let module_dir = Path::new(dir);
+    let entries: BTreeMap<String, String> =
+        parse_hash_manifest(&std::fs::read_to_string(manifest)?)?
+            .into_iter()
+            .map(|(digest, path)| (path, digest))
+            // installer.sh never extracts META-INF/
+            .filter(|(path, _)| !path.starts_with("META-INF/"))
+            .collect();
+
+    let mut bad = Vec::new();
+    let mut checked = 0;
+    let extracted = jwalk::WalkDir::new(module_dir)
+        .parallelism(jwalk::Parallelism::Serial)
+        .skip_hidden(false)
+        .into_iter()
+        .flatten()
+        .filter(|entry| !entry.file_type().is_dir());
+    for entry in extracted {
+        let path = entry.path();
+        let relative = path.strip_prefix(module_dir)?.to_string_lossy().to_string();
+        let Some(digest) = manifest_digest(&path)? else {
+            bad.push(format!("{relative} is not a file or symlink"));
+            continue;
+        };
+        match entries.get(&relative) {
+            None => bad.push(format!("{relative} is not in the manifest")),
+            Some(expected) if *expected != digest => {
+                bad.push(format!("{relative} doesn't match the manifest"));
+            }
+            Some(_) => checked += 1,
+        }
+    }
+    if !partial {
+        for path in entries.keys() {
+            if module_dir.join(path).symlink_metadata().is_err() {
+                bad.push(format!("{path} is in the manifest but not in the zip"));
+            }
+        }
+    }
+    ensure!(
+        bad.is_empty(),
+        "module files don't match {HASH_MANIFEST}:\n{}",
+        bad.join("\n")
+    );
+    if !partial {
+        println!("- Checked {checked} files against {HASH_MANIFEST}");
+    }
+    Ok(())
+}
+
+fn exec_install_script(
+    module_file: &str,
+    tmp_base: Option<&Path>,
+    log_file: &Path,
+    hash_manifest: Option<&Path>,
+    arch_trees: bool,
+) -> Result<()> {
     let realpath = std::fs::canonicalize(module_file)
         .with_context(|| format!("realpath: {module_file} failed"))?;
 
-    let result = Command::new(assets::BUSYBOX_PATH)
+    // the installer removes its TMPDIR, so never hand it the user's directory itself
+    let tmp_dir = tmp_base
+        .map(|base| tempdir::TempDir::new_in(base, "ksu_install"))
+        .transpose()?;
+
+    let log = Arc::new(Mutex::new(File::create(log_file)?));
+    let mut command = Command::new(assets::BUSYBOX_PATH);
+    if let Some(tmp_dir) = &tmp_dir {
+        command.env("KSU_INSTALL_TMPDIR", tmp_dir.path());
+    }
+    if let Some(manifest) = hash_manifest {
+        command.env("KSU_HASH_MANIFEST", manifest);
+    }
+    if arch_trees {
+        command
+            .env("KSU_ARCH_TREE", assets::DEVICE_ARCH)
+            .env("KSU_ARCH_TREES", MODULE_ARCHES.join(" "));
+    }
+    let mut child = command
         .args(["sh", "-c", INSTALL_MODULE_SCRIPT])
         .env("ASH_STANDALONE", "1")
-        .env(
-            "PATH",
-            format!(
-                "{}:{}",
-                env_var("PATH").unwrap(),
-                defs::BINARY_DIR.trim_end_matches('/')
-            ),
-        )
+        .env("PATH", get_script_path_env())
         .env("KSU", "true")
         .env("KSU_KERNEL_VER_CODE", ksucalls::get_version().to_string())
         .env("KSU_VER", defs::VERSION_NAME)
         .env("KSU_VER_CODE", defs::VERSION_CODE)
         .env("OUTFD", "1")
         .env("ZIPFILE", realpath)
-        .status()?;
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stdout = child
+        .stdout
+        .take()
+        .map(|out| tee_lines(out, std::io::stdout(), log.clone()));
+    let stderr = child
+        .stderr
+        .take()
+        .map(|err| tee_lines(err, std::io::stderr(), log));
+    let result = child.wait()?;
+    for tee in [stdout, stderr].into_iter().flatten() {
+        let _ = tee.join();
+    }
     ensure!(result.success(), "Failed to install module script");
     Ok(())
 }
@@ -131,56 +346,102 @@ fn foreach_active_module(f: impl FnMut(&Path) -> Result<()>) -> Result<()> {
     foreach_module(ModuleType::Active, f)
 }
 
+// modules often ship the same rules, they are applied as one batch with the duplicates dropped
 pub fn load_sepolicy_rule() -> Result<()> {
+    let rule_files: Vec<PathBuf> = sepolicy_rule_files()?
+        .into_iter()
+        .map(|(_, rule_file)| rule_file)
+        .collect();
+    sepolicy::apply_files(&rule_files, true)
+}
+
+/// sepolicy.rule of the active modules, with the module id
+pub fn sepolicy_rule_files() -> Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
     foreach_active_module(|path| {
         let rule_file = path.join("sepolicy.rule");
-        if !rule_file.exists() {
-            return Ok(());
-        }
-        info!("load policy: {}", &rule_file.display());
-
-        if sepolicy::apply_file(&rule_file).is_err() {
-            warn!("Failed to load sepolicy.rule for {}", &rule_file.display());
+        if let (true, Some(id)) = (rule_file.exists(), path.file_name()) {
+            files.push((id.to_string_lossy().to_string(), rule_file));
         }
         Ok(())
     })?;
-
-    Ok(())
+    files.sort();
+    Ok(files)
 }
 
-fn exec_script<T: AsRef<Path>>(path: T, wait: bool) -> Result<()> {
-    info!("exec {}", path.as_ref().display());
+// environment of the module scripts, on top of the environment inherited from ksud
+fn script_env() -> Vec<(&'static str, String)> {
+    vec![
+        ("ASH_STANDALONE", "1".to_owned()),
+        ("KSU", "true".to_owned()),
+        ("KSU_KERNEL_VER_CODE", ksucalls::get_version().to_string()),
+        ("KSU_VER_CODE", defs::VERSION_CODE.to_owned()),
+        ("KSU_VER", defs::VERSION_NAME.to_owned()),
+        ("PATH", get_script_path_env()),
+    ]
+}
 
-    let mut command = &mut Command::new(assets::BUSYBOX_PATH);
+fn script_command(path: &Path) -> Command {
+    let mut command = Command::new(assets::BUSYBOX_PATH);
     #[cfg(unix)]
     {
-        command = command.process_group(0);
-        command = unsafe {
+        command.process_group(0);
+        unsafe {
             command.pre_exec(|| {
                 // ignore the error?
                 switch_cgroups();
                 Ok(())
-            })
-        };
+            });
+        }
     }
-    command = command
-        .current_dir(path.as_ref().parent().unwrap())
+    command
+        .current_dir(path.parent().unwrap())
         .arg("sh")
-        .arg(path.as_ref())
-        .env("ASH_STANDALONE", "1")
-        .env("KSU", "true")
-        .env("KSU_KERNEL_VER_CODE", ksucalls::get_version().to_string())
-        .env("KSU_VER_CODE", defs::VERSION_CODE)
-        .env("KSU_VER", defs::VERSION_NAME)
-        .env(
-            "PATH",
-            format!(
-                "{}:{}",
-                env_var("PATH").unwrap(),
-                defs::BINARY_DIR.trim_end_matches('/')
-            ),
+        .arg(path)
+        .envs(script_env());
+    command
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn start_time(pid: u32) -> Option<u64> {
+    let process = procfs::process::Process::new(pid as i32).ok()?;
+    process.stat().ok().map(|stat| stat.starttime)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn start_time(_pid: u32) -> Option<u64> {
+    None
+}
+
+// service.sh runs in its own process group, remember it to pause and resume the module later.
+// the start time of service.sh tells whether the group is still the same once its pgid is reused
+fn exec_service_script(module: &Path, script_path: &Path) -> Result<()> {
+    info!("exec {}", script_path.display());
+    let child = script_command(script_path)
+        .spawn()
+        .map_err(|err| anyhow!("Failed to exec {}: {}", script_path.display(), err))?;
+    let Some(id) = module.file_name() else {
+        return Ok(());
+    };
+    ensure_dir_exists(defs::MODULE_SERVICE_PGID_DIR)?;
+    let pgid_file = Path::new(defs::MODULE_SERVICE_PGID_DIR).join(id);
+    let Some(started) = start_time(child.id()) else {
+        warn!("{} exited at once", script_path.display());
+        return Ok(());
+    };
+    if let Err(e) = std::fs::write(pgid_file, format!("{} {started}", child.id())) {
+        warn!(
+            "Failed to record the process group of {}: {e}",
+            script_path.display()
         );
+    }
+    Ok(())
+}
+
+fn exec_script<T: AsRef<Path>>(path: T, wait: bool) -> Result<()> {
+    info!("exec {}", path.as_ref().display());
 
+    let mut command = script_command(path.as_ref());
     let result = if wait {
         command.status().map(|_| ())
     } else {
@@ -189,19 +450,127 @@ fn exec_script<T: AsRef<Path>>(path: T, wait: bool) -> Result<()> {
     result.map_err(|err| anyhow!("Failed to exec {}: {}", path.as_ref().display(), err))
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ModuleStage {
+    PostFsData,
+    PostMount,
+    Service,
+    BootCompleted,
+    PostUnlock,
+    OnShutdown,
+}
+
+impl ModuleStage {
+    const ALL: [ModuleStage; 6] = [
+        ModuleStage::PostFsData,
+        ModuleStage::PostMount,
+        ModuleStage::Service,
+        ModuleStage::BootCompleted,
+        ModuleStage::PostUnlock,
+        ModuleStage::OnShutdown,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            ModuleStage::PostFsData => "post-fs-data",
+            ModuleStage::PostMount => "post-mount",
+            ModuleStage::Service => "service",
+            ModuleStage::BootCompleted => "boot-completed",
+            ModuleStage::PostUnlock => "post-unlock",
+            ModuleStage::OnShutdown => "on-shutdown",
+        }
+    }
+}
+
+// skip_service, skip_post_fs_data, ... in the module dir
+fn skip_stage_file_name(stage: &str) -> String {
+    format!("skip_{}", stage.replace('-', "_"))
+}
+
+fn skipped_stages(module: &Path) -> Vec<&'static str> {
+    ModuleStage::ALL
+        .iter()
+        .map(|stage| stage.as_str())
+        .filter(|stage| module.join(skip_stage_file_name(stage)).exists())
+        .collect()
+}
+
 pub fn exec_stage_script(stage: &str, block: bool) -> Result<()> {
+    exec_stage_script_if(stage, block, |_| true)
+}
+
+fn exec_stage_script_if(stage: &str, block: bool, filter: impl Fn(&Path) -> bool) -> Result<()> {
     foreach_active_module(|module| {
         let script_path = module.join(format!("{stage}.sh"));
-        if !script_path.exists() {
+        if !script_path.exists() || !filter(module) {
+            return Ok(());
+        }
+        if module.join(skip_stage_file_name(stage)).exists() {
+            info!(
+                "{} is skipped by {}",
+                script_path.display(),
+                skip_stage_file_name(stage)
+            );
             return Ok(());
         }
 
+        if stage == ModuleStage::Service.as_str() && !block {
+            return exec_service_script(module, &script_path);
+        }
         exec_script(&script_path, block)
     })?;
 
     Ok(())
 }
 
+/// exec boot-completed.sh of the modules which set `rerunBootCompleted=true` in module.prop,
+/// boot-completed.sh of other modules only run once per boot.
+pub fn exec_boot_completed_rerun_scripts(block: bool) -> Result<()> {
+    exec_stage_script_if("boot-completed", block, |module| {
+        read_module_prop(module).is_ok_and(|prop| {
+            prop.get("rerunBootCompleted")
+                .is_some_and(|v| v.trim() == "true")
+        })
+    })
+}
+
+/// Run on-shutdown.sh of the active modules in parallel, the ones still running after
+/// `timeout` are killed along with their children, shutdown can't wait for them.
+pub fn exec_shutdown_scripts(timeout: Duration) -> Result<()> {
+    let stage = ModuleStage::OnShutdown.as_str();
+    let mut children = Vec::new();
+    foreach_active_module(|module| {
+        let script_path = module.join(format!("{stage}.sh"));
+        if !script_path.exists() || module.join(skip_stage_file_name(stage)).exists() {
+            return Ok(());
+        }
+        info!("exec {}", script_path.display());
+        match script_command(&script_path).spawn() {
+            Ok(child) => children.push((script_path, child)),
+            Err(e) => warn!("Failed to exec {}: {e}", script_path.display()),
+        }
+        Ok(())
+    })?;
+
+    let deadline = Instant::now() + timeout;
+    while !children.is_empty() && Instant::now() < deadline {
+        children.retain_mut(|(_, child)| matches!(child.try_wait(), Ok(None)));
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    for (script_path, mut child) in children {
+        warn!("{} exceeded {timeout:?}, kill it", script_path.display());
+        // the script runs in its own process group, kill whatever it started too
+        #[cfg(unix)]
+        unsafe {
+            libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+        }
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+    Ok(())
+}
+
 pub fn exec_common_scripts(dir: &str, wait: bool) -> Result<()> {
     let script_dir = Path::new(defs::ADB_DIR).join(dir);
     if !script_dir.exists() {
@@ -224,24 +593,113 @@ pub fn exec_common_scripts(dir: &str, wait: bool) -> Result<()> {
     Ok(())
 }
 
-pub fn load_system_prop() -> Result<()> {
-    foreach_active_module(|module| {
-        let system_prop = module.join("system.prop");
-        if !system_prop.exists() {
-            return Ok(());
+// system.prop is a list of key=value, the same format that `resetprop --file` accepts
+fn read_system_prop(system_prop: &Path) -> Result<Vec<(String, String)>> {
+    let content = std::fs::read_to_string(system_prop)?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect())
+}
+
+fn read_system_prop_keys(system_prop: &Path) -> Result<Vec<String>> {
+    Ok(read_system_prop(system_prop)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect())
+}
+
+// warn about props which more than one module sets to different values, `modules` is in
+// the order they are applied, so the last one of them wins
+fn warn_system_prop_conflicts(modules: &[PathBuf]) {
+    let mut writers: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+    for module in modules {
+        let id = module.file_name().unwrap_or_default().to_string_lossy();
+        let Ok(props) = read_system_prop(&module.join("system.prop")) else {
+            continue;
+        };
+        for (key, value) in props {
+            writers
+                .entry(key)
+                .or_default()
+                .push((id.to_string(), value));
+        }
+    }
+
+    for (key, writers) in writers {
+        if writers.iter().all(|(_, value)| *value == writers[0].1) {
+            continue;
         }
-        info!("load {} system.prop", module.display());
+        let competing: Vec<String> = writers
+            .iter()
+            .map(|(id, value)| format!("{id}={value}"))
+            .collect();
+        let (winner, _) = writers.last().unwrap();
+        warn!(
+            "system.prop conflict on {key}: {}, {winner} wins",
+            competing.join(", ")
+        );
+    }
+}
+
+struct PropChange {
+    key: String,
+    old: Option<String>,
+    new: Option<String>,
+}
 
-        // resetprop -n --file system.prop
-        Command::new(assets::RESETPROP_PATH)
-            .arg("-n")
-            .arg("--file")
-            .arg(&system_prop)
-            .status()
-            .with_context(|| format!("Failed to exec {}", system_prop.display()))?;
+/// apply system.prop of the module, returns the props it set with their old and new values
+fn apply_system_prop(module: &Path) -> Result<Vec<PropChange>> {
+    let system_prop = module.join("system.prop");
+    if !system_prop.exists() {
+        return Ok(Vec::new());
+    }
+    info!("load {} system.prop", module.display());
+
+    let keys = read_system_prop_keys(&system_prop)?;
+    let old_values: Vec<Option<String>> = keys.iter().map(|key| getprop(key)).collect();
+
+    // resetprop -n --file system.prop
+    Command::new(assets::RESETPROP_PATH)
+        .arg("-n")
+        .arg("--file")
+        .arg(&system_prop)
+        .status()
+        .with_context(|| format!("Failed to exec {}", system_prop.display()))?;
+
+    let mut changes = Vec::new();
+    for (key, old) in keys.into_iter().zip(old_values) {
+        let new = getprop(&key);
+        info!(
+            "{}: set {key}: {} -> {}",
+            module.display(),
+            old.as_deref().unwrap_or("<unset>"),
+            new.as_deref().unwrap_or("<unset>")
+        );
+        changes.push(PropChange { key, old, new });
+    }
+    Ok(changes)
+}
 
+/// apply system.prop of the active modules in the order of their id, so a prop set by more than
+/// one of them always ends up with the value of the same module
+pub fn load_system_prop() -> Result<()> {
+    let mut modules = Vec::new();
+    foreach_active_module(|module| {
+        if module.join("system.prop").exists() {
+            modules.push(module.to_path_buf());
+        }
         Ok(())
     })?;
+    modules.sort();
+
+    warn_system_prop_conflicts(&modules);
+    for module in &modules {
+        apply_system_prop(module)?;
+    }
 
     Ok(())
 }
@@ -261,6 +719,11 @@ pub fn prune_modules() -> Result<()> {
             if let Err(e) = remove_dir_all(module) {
                 warn!("Failed to remove {}: {}", module.display(), e);
             }
+            if let Some(id) = module.file_name() {
+                remove_file(install_log_file(&id.to_string_lossy())).ok();
+                remove_file(install_manifest_file(&id.to_string_lossy())).ok();
+                remove_file(hash_manifest_file(&id.to_string_lossy())).ok();
+            }
         } else {
             remove_file(module.join(defs::UPDATE_FILE_NAME)).ok();
         }
@@ -271,6 +734,11 @@ pub fn prune_modules() -> Result<()> {
 }
 
 pub fn handle_updated_modules() -> Result<()> {
+    // don't move half installed modules into place
+    if let Err(e) = recover_interrupted_installs() {
+        warn!("Failed to clean up interrupted installs: {e}");
+    }
+
     let modules_root = Path::new(MODULE_DIR);
     foreach_module(ModuleType::Updated, |module| {
         if !module.is_dir() {
@@ -284,8 +752,8 @@ pub fn handle_updated_modules() -> Result<()> {
                     log::error!("Failed to remove old {}: {}", old_dir.display(), e);
                 }
             }
-            if let Err(e) = rename(module, &old_dir) {
-                log::error!("Failed to move new module {}: {}", module.display(), e);
+            if let Err(e) = move_dir(module, &old_dir) {
+                log::error!("Failed to move new module {}: {:#}", module.display(), e);
             }
         }
         Ok(())
@@ -293,124 +761,1428 @@ pub fn handle_updated_modules() -> Result<()> {
     Ok(())
 }
 
-pub fn install_module(zip: &str) -> Result<()> {
-    fn inner(zip: &str) -> Result<()> {
-        ensure_boot_completed()?;
+fn install_marker(id: &str) -> PathBuf {
+    Path::new(defs::MODULE_INSTALL_STATE_DIR).join(format!("{id}.installing"))
+}
 
-        // print banner
-        println!(include_str!("banner"));
+// what the install marker holds while the pending update is moved away
+const SUPERSEDING: &str = "superseding";
 
-        assets::ensure_binaries(false).with_context(|| "Failed to extract assets")?;
+fn superseded_update_dir(id: &str) -> PathBuf {
+    Path::new(defs::MODULE_INSTALL_STATE_DIR).join(format!("{id}.superseded"))
+}
 
-        // first check if working dir is usable
-        ensure_dir_exists(defs::WORKING_DIR).with_context(|| "Failed to create working dir")?;
-        ensure_dir_exists(defs::BINARY_DIR).with_context(|| "Failed to create bin dir")?;
+// move the pending update which was superseded by a failed install back
+fn restore_superseded_update(id: &str) -> bool {
+    let update_module_dir = Path::new(MODULE_UPDATE_DIR).join(id);
+    if move_dir(&superseded_update_dir(id), &update_module_dir).is_err() {
+        return false;
+    }
+    copy(
+        update_module_dir.join("module.prop"),
+        Path::new(MODULE_DIR).join(id).join("module.prop"),
+    )
+    .ok();
+    true
+}
 
-        // read the module_id from zip, if failed it will return early.
-        let mut buffer: Vec<u8> = Vec::new();
-        let entry_path = PathBuf::from_str("module.prop")?;
-        let zip_path = PathBuf::from_str(zip)?;
-        let zip_path = zip_path.canonicalize()?;
-        zip_extract_file_to_memory(&zip_path, &entry_path, &mut buffer)?;
-
-        let mut module_prop = HashMap::new();
-        PropertiesIter::new_with_encoding(Cursor::new(buffer), encoding_rs::UTF_8).read_into(
-            |k, v| {
-                module_prop.insert(k, v);
-            },
-        )?;
-        info!("module prop: {:?}", module_prop);
+// an install which was killed (e.g. the device shut down) leaves partially extracted files
+// behind, drop them and bring back the pending update it superseded, if any.
+// extraction can't be resumed, the install simply starts over.
+fn recover_interrupted_install(id: &str) -> Result<bool> {
+    let marker = install_marker(id);
+    if !marker.exists() {
+        return Ok(false);
+    }
+    warn!("{id}: previous install was interrupted, cleaning up");
+    if std::fs::read_to_string(&marker).is_ok_and(|state| state == SUPERSEDING)
+        && !superseded_update_dir(id).exists()
+    {
+        // killed before the pending update was moved away, it is still intact
+        remove_file(&marker)?;
+        return Ok(true);
+    }
+    let update_module_dir = Path::new(MODULE_UPDATE_DIR).join(id);
+    if update_module_dir.exists() {
+        remove_dir_all(&update_module_dir)?;
+    }
+    if superseded_update_dir(id).exists() && restore_superseded_update(id) {
+        info!("{id}: restored the superseded pending update");
+    }
+    remove_file(pending_hash_manifest_file(id)).ok();
+    remove_file(&marker)?;
+    Ok(true)
+}
 
-        let Some(module_id) = module_prop.get("id") else {
-            bail!("module id not found in module.prop!");
-        };
-        let module_id = module_id.trim();
+fn recover_interrupted_installs() -> Result<()> {
+    let Ok(dir) = std::fs::read_dir(defs::MODULE_INSTALL_STATE_DIR) else {
+        return Ok(());
+    };
+    for entry in dir.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(id) = name.strip_suffix(".installing") {
+            recover_interrupted_install(id)?;
+        }
+    }
+    Ok(())
+}
 
-        let zip_uncompressed_size = get_zip_uncompressed_size(zip)?;
+// a bundle is a zip which only contains module zips, without module.prop at the root
+fn is_module_bundle(zip: &str) -> Result<bool> {
+    let mut archive = zip::ZipArchive::new(File::open(zip)?)?;
+    let mut has_module_zip = false;
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i)?;
+        if entry.is_dir() {
+            continue;
+        }
+        if !entry.name().ends_with(".zip") {
+            return Ok(false);
+        }
+        has_module_zip = true;
+    }
+    Ok(has_module_zip)
+}
 
-        info!(
-            "zip uncompressed size: {}",
-            humansize::format_size(zip_uncompressed_size, humansize::DECIMAL)
-        );
+#[derive(Debug, Default)]
+pub struct InstallOptions {
+    /// for a bundle, continue installing the rest if one module fails
+    pub continue_on_error: bool,
+    /// pin the signer of a module on its first install, an existing pin is enforced regardless
+    pub trust_tofu: bool,
+    /// only pin a new signer when it is explicitly accepted by `accept_signer`
+    pub tofu_strict: bool,
+    pub accept_signer: Option<String>,
+    /// apply the sepolicy rules, files and system.prop of the module without reboot
+    pub now: bool,
+    /// refuse to replace an update of the module which is still waiting for reboot
+    pub no_supersede: bool,
+    /// where to stage the install instead of the default temp dirs, `KSUD_TMPDIR` if unset
+    pub tmp_dir: Option<String>,
+    /// keep the sepolicy.rule of the module aside instead of applying it
+    pub skip_sepolicy: bool,
+    /// leave out VCS dirs, macOS metadata and the docs at the top of the module
+    pub strip_junk: bool,
+    /// install even if the module conflicts with an installed one, see `conflicts=` in module.prop
+    pub ignore_conflicts: bool,
+    /// name of a zip read from stdin in the logs and messages, it doesn't change the install
+    pub stdin_zip_name: Option<String>,
+    /// reboot once installed, to the target the module asks for with `reboot=` in module.prop
+    pub reboot: bool,
+    /// print the result as a json line after the install messages
+    pub json: bool,
+}
 
-        println!("- Preparing Zip");
-        println!(
-            "- Module size: {}",
-            humansize::format_size(zip_uncompressed_size, humansize::DECIMAL)
-        );
+fn resolve_tmp_dir(opts: &InstallOptions) -> Result<Option<PathBuf>> {
+    let Some(dir) = opts
+        .tmp_dir
+        .clone()
+        .or_else(|| env_var("KSUD_TMPDIR").ok().filter(|dir| !dir.is_empty()))
+    else {
+        return Ok(None);
+    };
+    let dir = PathBuf::from(dir);
+    ensure!(
+        dir.is_dir(),
+        "temp dir {} is not a directory",
+        dir.display()
+    );
+    // fails early if the filesystem is read-only or full
+    tempdir::TempDir::new_in(&dir, "ksu_check")
+        .with_context(|| format!("temp dir {} is not writable", dir.display()))?;
 
-        // ensure modules_update exists
-        ensure_dir_exists(MODULE_UPDATE_DIR)?;
-        setsyscon(MODULE_UPDATE_DIR)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if std::fs::metadata(&dir)?.dev() != std::fs::metadata(defs::ADB_DIR)?.dev() {
+            println!(
+                "- Warning: {} is not on the same filesystem as {}, the install will be slower",
+                dir.display(),
+                defs::ADB_DIR
+            );
+        }
+    }
 
-        let update_module_dir = Path::new(MODULE_UPDATE_DIR).join(module_id);
-        ensure_clean_dir(&update_module_dir)?;
-        info!("module dir: {}", update_module_dir.display());
+    info!("install temp dir: {}", dir.display());
+    Ok(Some(dir))
+}
 
-        let do_install = || -> Result<()> {
-            // unzip the image and move it to modules_update/<id> dir
-            let file = File::open(zip)?;
-            let mut archive = zip::ZipArchive::new(file)?;
-            archive.extract(&update_module_dir)?;
+// VCS and macOS metadata, removed wherever they are in the module
+const JUNK_NAMES: &[&str] = &[
+    ".git",
+    ".gitignore",
+    ".gitattributes",
+    "__MACOSX",
+    ".DS_Store",
+];
+
+// whether strip_junk removes a path of the file list, the docs are only junk at the top of the
+// module which the list doesn't cover
+fn is_junk_path(path: &str) -> bool {
+    Path::new(path)
+        .components()
+        .any(|part| JUNK_NAMES.contains(&part.as_os_str().to_string_lossy().as_ref()))
+}
 
-            // set permission and selinux context for $MOD/system
-            let module_system_dir = update_module_dir.join("system");
-            if module_system_dir.exists() {
-                #[cfg(unix)]
-                set_permissions(&module_system_dir, Permissions::from_mode(0o755))?;
-                restore_syscon(&module_system_dir)?;
+// remove the junk of `dir` and below, `top` is the module dir itself where the docs are junk too
+fn strip_junk_in(dir: &Path, top: bool, stripped: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)?.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let file_type = entry.file_type()?;
+        let is_doc = top && file_type.is_file() && name.to_lowercase().ends_with(".md");
+        if JUNK_NAMES.contains(&name.as_str()) || is_doc {
+            if file_type.is_dir() {
+                remove_dir_all(entry.path())?;
+            } else {
+                remove_file(entry.path())?;
             }
-
-            exec_install_script(zip)?;
-
-            let module_dir = Path::new(MODULE_DIR).join(module_id);
-            ensure_dir_exists(&module_dir)?;
-            copy(
-                update_module_dir.join("module.prop"),
-                module_dir.join("module.prop"),
-            )?;
-            ensure_file_exists(module_dir.join(UPDATE_FILE_NAME))?;
-
-            info!("Module install successfully!");
-
-            Ok(())
-        };
-        let result = do_install();
-        if result.is_err() {
-            remove_dir_all(&update_module_dir).ok();
+            stripped.push(entry.path());
+        } else if file_type.is_dir() {
+            strip_junk_in(&entry.path(), false, stripped)?;
         }
-        result
-    }
-    let result = inner(zip);
-    if let Err(ref e) = result {
-        println!("- Error: {e}");
     }
-    result
+    Ok(())
 }
 
-pub fn uninstall_module(id: &str) -> Result<()> {
-    mark_module_state(id, defs::REMOVE_FILE_NAME, true)
+/// the junk removed from the module dir, relative to it and sorted
+fn strip_junk(module_dir: &Path) -> Result<Vec<String>> {
+    let mut stripped = Vec::new();
+    strip_junk_in(module_dir, true, &mut stripped)?;
+    let mut stripped: Vec<String> = stripped
+        .iter()
+        .filter_map(|path| path.strip_prefix(module_dir).ok())
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+    stripped.sort();
+    Ok(stripped)
 }
 
-pub fn run_action(id: &str) -> Result<()> {
-    let action_script_path = format!("/data/adb/modules/{}/action.sh", id);
-    exec_script(&action_script_path, true)
+// renamed so that it is never loaded, the user can review it and apply it by hand
+fn skip_sepolicy_rule(module_dir: &Path, id: &str) -> Result<()> {
+    let rule_file = module_dir.join("sepolicy.rule");
+    if !rule_file.exists() {
+        return Ok(());
+    }
+    let rules = std::fs::read_to_string(&rule_file)?;
+    rename(&rule_file, module_dir.join(SKIPPED_SEPOLICY_RULE))?;
+
+    println!("- Skipped sepolicy.rule:");
+    for rule in rules
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    {
+        println!("  {rule}");
+    }
+    println!(
+        "- Apply them with: ksud sepolicy apply {}",
+        Path::new(MODULE_DIR)
+            .join(id)
+            .join(SKIPPED_SEPOLICY_RULE)
+            .display()
+    );
+    Ok(())
 }
 
-pub fn enable_module(id: &str) -> Result<()> {
-    mark_module_state(id, defs::DISABLE_FILE_NAME, false)
+// move everything in `src` into `dst`, the files of `src` win
+fn merge_dir(src: &Path, dst: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(src)?.flatten() {
+        let target = dst.join(entry.file_name());
+        let is_dir = entry.file_type()?.is_dir();
+        if is_dir && target.is_dir() && !target.is_symlink() {
+            merge_dir(&entry.path(), &target)?;
+            continue;
+        }
+        if target.is_dir() && !target.is_symlink() {
+            remove_dir_all(&target)?;
+        } else if target.symlink_metadata().is_ok() {
+            remove_file(&target)?;
+        }
+        rename(entry.path(), &target)?;
+    }
+    remove_dir_all(src)?;
+    Ok(())
 }
 
-pub fn disable_module(id: &str) -> Result<()> {
-    mark_module_state(id, defs::DISABLE_FILE_NAME, true)
+// a multi-arch module sets `archTrees=true` in module.prop and ships arm64/, x86_64/, ... and
+// optionally common/ at the top of the zip; in other modules these are plain dirs.
+fn merges_arch_trees(module_prop: &BTreeMap<String, String>) -> bool {
+    module_prop
+        .get("archTrees")
+        .is_some_and(|v| v.trim() == "true")
 }
 
-pub fn disable_all_modules() -> Result<()> {
-    mark_all_modules(defs::DISABLE_FILE_NAME)
-}
+// common/ and the tree of the device arch are merged into the module dir, the other arch trees
+// are dropped; without a tree for the device arch nothing is merged. installer.sh extracts the
+// zip again and does the same, this is what customize.sh and the default permissions see.
+fn merge_arch_trees(module_dir: &Path) -> Result<()> {
+    let arch = assets::DEVICE_ARCH;
+    if !module_dir.join(arch).is_dir() {
+        warn!("module has no files for {arch}, its arch trees are left as they are");
+        return Ok(());
+    }
 
-fn mark_all_modules(flag_file: &str) -> Result<()> {
-    let dir = std::fs::read_dir(MODULE_DIR)?;
+    let common = module_dir.join(COMMON_TREE);
+    if common.is_dir() {
+        merge_dir(&common, module_dir)?;
+    }
+    merge_dir(&module_dir.join(arch), module_dir)?;
+    for other in MODULE_ARCHES.iter().filter(|other| **other != arch) {
+        let tree = module_dir.join(other);
+        if tree.is_dir() {
+            remove_dir_all(tree)?;
+        }
+    }
+    info!("merged files for {arch}");
+    Ok(())
+}
+
+struct PermEntry {
+    path: String,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    context: Option<String>,
+}
+
+// one `<path> <uid> <gid> <mode> [context]` per line, path is relative to the module dir,
+// e.g. `system/bin/foo 0 2000 0755 u:object_r:system_file:s0`
+fn parse_permissions(module_dir: &Path, content: &str) -> Result<Vec<PermEntry>> {
+    let mut entries = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [path, uid, gid, mode, rest @ ..] = fields.as_slice() else {
+            bail!(
+                "{PERMISSIONS_FILE_NAME}:{}: expect <path> <uid> <gid> <mode> [context]",
+                i + 1
+            );
+        };
+        ensure!(
+            rest.len() <= 1,
+            "{PERMISSIONS_FILE_NAME}:{}: too many fields",
+            i + 1
+        );
+
+        // never touch anything outside of the module
+        let relative = Path::new(path);
+        ensure!(
+            relative.is_relative()
+                && relative
+                    .components()
+                    .all(|c| matches!(c, std::path::Component::Normal(_))),
+            "{PERMISSIONS_FILE_NAME}:{}: {path} is not a plain relative path",
+            i + 1
+        );
+        let metadata = std::fs::symlink_metadata(module_dir.join(relative))
+            .with_context(|| format!("{PERMISSIONS_FILE_NAME}:{}: {path} not found", i + 1))?;
+        ensure!(
+            !metadata.file_type().is_symlink(),
+            "{PERMISSIONS_FILE_NAME}:{}: {path} is a symlink",
+            i + 1
+        );
+        // a symlinked parent dir could lead out of it too
+        ensure!(
+            module_dir
+                .join(relative)
+                .canonicalize()?
+                .starts_with(module_dir.canonicalize()?),
+            "{PERMISSIONS_FILE_NAME}:{}: {path} is outside of the module",
+            i + 1
+        );
+
+        let mode = u32::from_str_radix(mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .with_context(|| format!("{PERMISSIONS_FILE_NAME}:{}: bad mode {mode}", i + 1))?;
+        let context = rest.first().map(|con| con.to_string());
+        if let Some(con) = &context {
+            ensure!(
+                con.split(':').count() >= 4,
+                "{PERMISSIONS_FILE_NAME}:{}: bad context {con}",
+                i + 1
+            );
+        }
+        entries.push(PermEntry {
+            path: path.to_string(),
+            uid: uid
+                .parse()
+                .with_context(|| format!("{PERMISSIONS_FILE_NAME}:{}: bad uid {uid}", i + 1))?,
+            gid: gid
+                .parse()
+                .with_context(|| format!("{PERMISSIONS_FILE_NAME}:{}: bad gid {gid}", i + 1))?,
+            mode,
+            context,
+        });
+    }
+    Ok(entries)
+}
+
+// the permissions manifest of the module, applied after customize.sh so it has the last word.
+// all entries are validated before any of them is applied.
+fn apply_permissions(module_dir: &Path) -> Result<()> {
+    let manifest = module_dir.join(PERMISSIONS_FILE_NAME);
+    if !manifest.exists() {
+        return Ok(());
+    }
+    let entries = parse_permissions(module_dir, &std::fs::read_to_string(&manifest)?)?;
+
+    for entry in &entries {
+        let path = module_dir.join(&entry.path);
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::lchown(&path, Some(entry.uid), Some(entry.gid))
+                .with_context(|| format!("Failed to chown {}", entry.path))?;
+            set_permissions(&path, Permissions::from_mode(entry.mode))
+                .with_context(|| format!("Failed to chmod {}", entry.path))?;
+        }
+        if let Some(con) = &entry.context {
+            lsetfilecon(&path, con)?;
+        }
+        let mut perm = format!("{}:{} {:04o}", entry.uid, entry.gid, entry.mode);
+        if let Some(con) = &entry.context {
+            perm = format!("{perm} {con}");
+        }
+        println!("- Set {}: {perm}", entry.path);
+    }
+    Ok(())
+}
+
+// apply the sepolicy.rule and the files of a freshly installed module together. The rules are
+// checked and the mounts planned before anything changes, the mounts are rolled back if the
+// rules can't be applied. system.prop is applied at last.
+fn apply_module_now(module_dir: &Path) -> Result<()> {
+    let rule_file = module_dir.join("sepolicy.rule");
+    let has_rule = rule_file.exists();
+    if has_rule {
+        sepolicy::check_file_strict(&rule_file).context("Invalid sepolicy.rule")?;
+    }
+    let plan = crate::init_event::plan_module_now(module_dir)
+        .context("The module can only be mounted after reboot")?;
+
+    // we are in a private mount namespace, mount it in the global one
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    switch_mnt_ns(1)?;
+    let mounted = crate::init_event::mount_module_now(&plan)?;
+
+    if has_rule {
+        if let Err(e) = sepolicy::apply_file_strict(&rule_file) {
+            crate::init_event::unmount_module_now(&mounted);
+            return Err(
+                e.context("Failed to apply sepolicy.rule, some rules may remain until reboot")
+            );
+        }
+    }
+
+    println!("- Mounted {} files", mounted.len());
+
+    for change in apply_system_prop(module_dir)? {
+        println!(
+            "- Set {}: {} -> {}",
+            change.key,
+            change.old.as_deref().unwrap_or("<unset>"),
+            change.new.as_deref().unwrap_or("<unset>")
+        );
+    }
+    Ok(())
+}
+
+// returns the signer which should be pinned after the module is installed
+// an existing pin is always enforced, `trust_tofu` only decides whether a first one is created
+fn check_module_signer(zip: &str, id: &str, opts: &InstallOptions) -> Result<Option<String>> {
+    let pin_file = Path::new(defs::MODULE_SIGNER_DIR).join(id);
+    let pinned = std::fs::read_to_string(&pin_file).ok();
+    if pinned.is_none() && !opts.trust_tofu {
+        return Ok(None);
+    }
+    let (_, signer) =
+        apk_sign::get_apk_signature(zip).with_context(|| format!("{zip} is not signed"))?;
+
+    if let Some(pinned) = pinned {
+        let pinned = pinned.trim();
+        ensure!(
+            pinned == signer,
+            "signer of module {id} changed, pinned: {pinned}, got: {signer}"
+        );
+        info!("signer of module {id} matches the pinned one: {pinned}");
+        return Ok(None);
+    }
+
+    if opts.tofu_strict && opts.accept_signer.as_deref() != Some(signer.as_str()) {
+        bail!("signer {signer} of module {id} is not pinned, pass `--accept-signer {signer}` to trust it");
+    }
+    Ok(Some(signer))
+}
+
+/// `zip` may be `-` to read the zip from stdin
+pub fn install_module(zip: &str, opts: &InstallOptions) -> Result<()> {
+    fn inner(zip: &str, opts: &InstallOptions, tmp_base: Option<&Path>) -> Result<InstallResult> {
+        assets::ensure_binaries(false).with_context(|| "Failed to extract assets")?;
+
+        // first check if working dir is usable
+        ensure_dir_exists(defs::WORKING_DIR).with_context(|| "Failed to create working dir")?;
+        ensure_dir_exists(defs::BINARY_DIR).with_context(|| "Failed to create bin dir")?;
+
+        // read the module_id from zip, if failed it will return early.
+        let mut archive = zip::ZipArchive::new(File::open(zip)?)?;
+        let module_prop = parse_module_prop(archive.by_name("module.prop")?)?;
+        info!("module prop: {:?}", module_prop);
+
+        check_required_module_props(&module_prop)?;
+        check_min_busybox(&module_prop)?;
+        if !opts.ignore_conflicts {
+            check_install_conflicts(&module_prop)?;
+        }
+        // only an install which reboots depends on reboot=, it fails up front then
+        let reboot_target = match RebootTarget::of_module(&module_prop) {
+            Ok(target) => target,
+            Err(e) if !opts.reboot => {
+                println!("- Warning: {e}, system is assumed");
+                RebootTarget::System
+            }
+            Err(e) => return Err(e),
+        };
+        let installed = InstallResult {
+            reboot_target,
+            reboot_required: requires_reboot(&module_prop),
+        };
+        let module_id = module_prop["id"].trim();
+
+        let arch_trees = merges_arch_trees(&module_prop);
+        let zip_files: BTreeSet<&str> = archive.file_names().collect();
+        // a file or directory of the module, installer.sh merges common/ and the device arch
+        // tree into it
+        let in_zip = |path: &str| {
+            let mut paths = vec![path.to_owned()];
+            if arch_trees {
+                paths.push(format!("{COMMON_TREE}/{path}"));
+                paths.push(format!("{}/{path}", assets::DEVICE_ARCH));
+            }
+            paths.iter().any(|path| {
+                let dir = format!("{path}/");
+                zip_files
+                    .iter()
+                    .any(|name| *name == path.as_str() || name.starts_with(&dir))
+            })
+        };
+        if in_zip(defs::MODULE_WEB_DIR) && !in_zip(WEBUI_INDEX) {
+            println!(
+                "- Warning: the zip has {}/ but no {WEBUI_INDEX}, the WebUI won't load",
+                defs::MODULE_WEB_DIR
+            );
+        }
+
+        let new_signer = check_module_signer(zip, module_id, opts)?;
+
+        let hash_manifest = match archive.by_name(HASH_MANIFEST) {
+            Ok(mut entry) => {
+                let mut content = String::new();
+                entry.read_to_string(&mut content)?;
+                parse_hash_manifest(&content)?;
+                Some(content)
+            }
+            Err(_) => None,
+        };
+
+        let zip_uncompressed_size = get_zip_uncompressed_size(zip)?;
+
+        info!(
+            "zip uncompressed size: {}",
+            humansize::format_size(zip_uncompressed_size, humansize::DECIMAL)
+        );
+
+        println!("- Preparing Zip");
+        println!(
+            "- Module size: {}",
+            humansize::format_size(zip_uncompressed_size, humansize::DECIMAL)
+        );
+
+        // ensure modules_update exists
+        ensure_dir_exists(MODULE_UPDATE_DIR)?;
+        setsyscon(MODULE_UPDATE_DIR)?;
+
+        let update_module_dir = Path::new(MODULE_UPDATE_DIR).join(module_id);
+
+        ensure_dir_exists(defs::MODULE_INSTALL_STATE_DIR)?;
+        if recover_interrupted_install(module_id)? {
+            println!("- Cleaned up an interrupted install of {module_id}");
+        }
+
+        // removed once the install finished either way, if it is still there the install was
+        // killed. it is there before the pending update is moved away, which may be killed too
+        let marker = install_marker(module_id);
+
+        // the module was installed before without reboot, keep the queued update aside
+        // until the new one is installed, so a failed install doesn't lose both of them.
+        let superseded = if update_module_dir.exists() {
+            let pending_version = read_module_prop(&update_module_dir)
+                .ok()
+                .and_then(|prop| prop.get("version").cloned())
+                .unwrap_or_else(|| "unknown".to_owned());
+            ensure!(
+                !opts.no_supersede,
+                "Module {module_id} already has a pending update ({pending_version}), reboot to apply it first"
+            );
+            println!("- Found pending update {pending_version} of {module_id}, superseding it");
+            let backup_dir = superseded_update_dir(module_id);
+            if backup_dir.exists() {
+                remove_dir_all(&backup_dir)?;
+            }
+            std::fs::write(&marker, SUPERSEDING)?;
+            move_dir(&update_module_dir, &backup_dir)
+                .with_context(|| "Failed to move away the pending update")?;
+            Some((backup_dir, pending_version))
+        } else {
+            None
+        };
+
+        std::fs::write(&marker, "")?;
+
+        ensure_clean_dir(&update_module_dir)?;
+        info!("module dir: {}", update_module_dir.display());
+
+        let pending_manifest = pending_hash_manifest_file(module_id);
+        if let Some(content) = &hash_manifest {
+            std::fs::write(&pending_manifest, content)?;
+        }
+
+        let do_install = || -> Result<()> {
+            // unzip the image and move it to modules_update/<id> dir
+            let file = File::open(zip)?;
+            let mut archive = zip::ZipArchive::new(file)?;
+            archive.extract(&update_module_dir)?;
+            if arch_trees {
+                merge_arch_trees(&update_module_dir)?;
+            }
+
+            // set permission and selinux context for $MOD/system
+            let module_system_dir = update_module_dir.join("system");
+            if module_system_dir.exists() {
+                #[cfg(unix)]
+                set_permissions(&module_system_dir, Permissions::from_mode(0o755))?;
+                restore_syscon(&module_system_dir)?;
+            }
+
+            // what the zip provides, `module files` tells what customize.sh changed against it.
+            // the junk is stripped after installer.sh, it is no change of customize.sh
+            let mut manifest = overlay_files(&update_module_dir);
+            if opts.strip_junk {
+                manifest.retain(|file| !is_junk_path(file));
+            }
+            if let Err(e) = std::fs::write(install_manifest_file(module_id), manifest.join("\n")) {
+                warn!("Failed to save the file list of {module_id}: {e}");
+            }
+
+            // installer.sh checks the files it extracts against the hash manifest
+            exec_install_script(
+                zip,
+                tmp_base,
+                &install_log_file(module_id),
+                hash_manifest.as_ref().map(|_| pending_manifest.as_path()),
+                arch_trees,
+            )?;
+            // installer.sh extracts the zip again, so the junk is only gone after it ran
+            if opts.strip_junk {
+                for path in strip_junk(&update_module_dir)? {
+                    println!("- Skipped {path}");
+                }
+            }
+            apply_permissions(&update_module_dir)?;
+
+            if opts.skip_sepolicy {
+                skip_sepolicy_rule(&update_module_dir, module_id)?;
+            }
+
+            let module_dir = Path::new(MODULE_DIR).join(module_id);
+            ensure_dir_exists(&module_dir)?;
+            copy(
+                update_module_dir.join("module.prop"),
+                module_dir.join("module.prop"),
+            )?;
+            ensure_file_exists(module_dir.join(UPDATE_FILE_NAME))?;
+
+            if let Some(signer) = &new_signer {
+                ensure_dir_exists(defs::MODULE_SIGNER_DIR)?;
+                std::fs::write(Path::new(defs::MODULE_SIGNER_DIR).join(module_id), signer)?;
+                println!("- Pinned signer {signer} for module {module_id}");
+            }
+
+            info!("Module install successfully!");
+
+            Ok(())
+        };
+        let result = do_install();
+        if result.is_err() {
+            remove_dir_all(&update_module_dir).ok();
+            remove_file(&pending_manifest).ok();
+            if let Some((_, pending_version)) = &superseded {
+                if restore_superseded_update(module_id) {
+                    println!("- Kept pending update {pending_version} of {module_id}");
+                }
+            }
+            remove_file(&marker).ok();
+            return result.map(|()| installed);
+        }
+
+        if let Some((backup_dir, pending_version)) = superseded {
+            remove_dir_all(backup_dir).ok();
+            let version = module_prop.get("version").map_or("unknown", String::as_str);
+            info!("{module_id}: pending update {pending_version} is replaced by {version}");
+            println!("- Replaced pending update {pending_version} with {version}");
+        }
+        // `module verify` checks against the manifest of the installed zip, or nothing
+        if hash_manifest.is_some() {
+            rename(&pending_manifest, hash_manifest_file(module_id))?;
+        } else {
+            remove_file(hash_manifest_file(module_id)).ok();
+        }
+        remove_file(&marker).ok();
+        record_history(module_id, "install");
+
+        // the module is installed now, failing to apply it only delays it until reboot
+        if opts.now {
+            println!("- Applying module now");
+            apply_module_now(&update_module_dir)
+                .with_context(|| "Module is installed, but failed to apply it now")?;
+        }
+        if installed.reboot_required {
+            println!("- {module_id} requires a reboot, reboot before using it");
+        }
+
+        Ok(installed)
+    }
+
+    // the modules of a bundle may ask for different targets, a bundle always reboots to system
+    fn install_bundle(
+        zip: &str,
+        opts: &InstallOptions,
+        tmp_base: Option<&Path>,
+    ) -> Result<InstallResult> {
+        let tmp_dir = match tmp_base {
+            Some(base) => tempdir::TempDir::new_in(base, "ksu_bundle")?,
+            None => tempdir::TempDir::new("ksu_bundle")?,
+        };
+        let mut archive = zip::ZipArchive::new(File::open(zip)?)?;
+        let mut installed = 0;
+        let mut reboot_required = false;
+        let mut failed = Vec::new();
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            if entry.is_dir() {
+                continue;
+            }
+            let name = entry.name().to_string();
+            // don't trust the entry name as a path, it may contain something like ../
+            let module_zip = tmp_dir.path().join(format!("{i}.zip"));
+            std::io::copy(&mut entry, &mut File::create(&module_zip)?)?;
+
+            println!("- Installing {name} from bundle");
+            match inner(&module_zip.to_string_lossy(), opts, tmp_base) {
+                Ok(result) => reboot_required |= result.reboot_required,
+                Err(e) => {
+                    println!("{}", red(&format!("- Error: {name}: {e}")));
+                    if !opts.continue_on_error {
+                        return Err(e.context(format!("Failed to install {name} from bundle")));
+                    }
+                    failed.push(name);
+                    continue;
+                }
+            }
+            installed += 1;
+        }
+
+        println!(
+            "- Bundle installed: {installed} succeeded, {} failed",
+            failed.len()
+        );
+        ensure!(
+            failed.is_empty(),
+            "Failed to install modules from bundle: {}",
+            failed.join(", ")
+        );
+        Ok(InstallResult {
+            reboot_target: RebootTarget::System,
+            reboot_required,
+        })
+    }
+
+    let display_name = if zip == "-" {
+        opts.stdin_zip_name.as_deref().unwrap_or("<stdin>")
+    } else {
+        zip
+    };
+    let result = ensure_boot_completed().and_then(|_| {
+        // print banner
+        println!(include_str!("banner"));
+
+        let tmp_base = resolve_tmp_dir(opts)?;
+        let tmp_base = tmp_base.as_deref();
+
+        // the installer needs a real file, keep the piped zip until the install is done
+        let stdin_dir = if zip == "-" {
+            let dir = match tmp_base {
+                Some(base) => tempdir::TempDir::new_in(base, "ksu_stdin")?,
+                None => tempdir::TempDir::new("ksu_stdin")?,
+            };
+            let stdin_zip = dir.path().join("module.zip");
+            std::io::copy(&mut std::io::stdin().lock(), &mut File::create(&stdin_zip)?)
+                .with_context(|| format!("Failed to read {display_name} from stdin"))?;
+            Some((dir, stdin_zip.to_string_lossy().to_string()))
+        } else {
+            None
+        };
+        let zip = stdin_dir.as_ref().map_or(zip, |(_, path)| path.as_str());
+
+        info!("installing {display_name}");
+        if is_module_bundle(zip)? {
+            info!("{display_name} is a module bundle");
+            install_bundle(zip, opts, tmp_base)
+        } else {
+            inner(zip, opts, tmp_base)
+        }
+    });
+    if let Err(ref e) = result {
+        if zip == "-" {
+            log::error!("Failed to install {display_name}: {e:?}");
+            println!("{}", red(&format!("- Error: {display_name}: {e}")));
+        } else {
+            println!("{}", red(&format!("- Error: {e}")));
+        }
+    }
+    let installed = result?;
+    if opts.json {
+        print_json(
+            serde_json::json!({
+                "reboot_required": installed.reboot_required,
+                "reboot_target": installed.reboot_target.name(),
+            }),
+            false,
+        )?;
+    }
+    if opts.reboot {
+        reboot_after_install(installed.reboot_target)?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug)]
+struct InstallResult {
+    reboot_target: RebootTarget,
+    /// `require_reboot=true` in module.prop, the module isn't safe to use before a reboot
+    reboot_required: bool,
+}
+
+fn requires_reboot(module_prop: &BTreeMap<String, String>) -> bool {
+    module_prop
+        .get("require_reboot")
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("true"))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RebootTarget {
+    System,
+    Recovery,
+    Bootloader,
+}
+
+impl RebootTarget {
+    // `reboot=recovery|system|bootloader` in module.prop, system if it isn't set
+    fn of_module(module_prop: &BTreeMap<String, String>) -> Result<Self> {
+        match module_prop.get("reboot").map(|v| v.trim()) {
+            None | Some("") | Some("system") => Ok(RebootTarget::System),
+            Some("recovery") => Ok(RebootTarget::Recovery),
+            Some("bootloader") => Ok(RebootTarget::Bootloader),
+            Some(other) => bail!(
+                "invalid reboot={other} in module.prop, expect recovery, system or bootloader"
+            ),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            RebootTarget::System => "system",
+            RebootTarget::Recovery => "recovery",
+            RebootTarget::Bootloader => "bootloader",
+        }
+    }
+}
+
+fn reboot_after_install(target: RebootTarget) -> Result<()> {
+    // someone is watching, let them say no; the manager passes --reboot only after asking
+    if std::io::stdin().is_terminal() {
+        print!("- Reboot to {} now? [y/N] ", target.name());
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if !answer.trim().eq_ignore_ascii_case("y") {
+            println!("- Reboot skipped");
+            return Ok(());
+        }
+    }
+
+    println!("- Rebooting to {}", target.name());
+    let mut command = Command::new("reboot");
+    if target != RebootTarget::System {
+        command.arg(target.name());
+    }
+    let status = command.status().with_context(|| "Failed to exec reboot")?;
+    ensure!(status.success(), "Failed to reboot to {}", target.name());
+    Ok(())
+}
+
+const REQUIRED_MODULE_PROPS: &[&str] = &["id"];
+const MODULE_PROP_DEFAULTS: &[(&str, &str)] = &[
+    ("name", ""),
+    ("version", ""),
+    ("versionCode", "0"),
+    ("author", ""),
+    ("description", ""),
+];
+
+fn check_required_module_props(module_prop: &BTreeMap<String, String>) -> Result<()> {
+    for field in REQUIRED_MODULE_PROPS {
+        ensure!(
+            module_prop
+                .get(*field)
+                .is_some_and(|v| !v.trim().is_empty()),
+            "module.prop is missing required field '{field}'"
+        );
+    }
+    Ok(())
+}
+
+// 1.36.1.git and v1.36.1 -> [1, 36, 1], the numeric parts are compared only
+fn parse_version(version: &str) -> Vec<u32> {
+    let version = version.trim();
+    version
+        .strip_prefix(['v', 'V'])
+        .unwrap_or(version)
+        .split(['.', '-'])
+        .map_while(|part| part.parse().ok())
+        .collect()
+}
+
+fn check_min_busybox(module_prop: &BTreeMap<String, String>) -> Result<()> {
+    let Some(required) = module_prop.get("minBusybox").map(|v| v.trim()) else {
+        return Ok(());
+    };
+    let current = assets::busybox_version().with_context(|| {
+        format!("module requires busybox {required}, but its version is unknown")
+    })?;
+    ensure!(
+        parse_version(&current) >= parse_version(required),
+        "module requires busybox {required} or newer, but the bundled busybox is {current}"
+    );
+    info!("busybox {current} satisfies minBusybox {required}");
+    Ok(())
+}
+
+// the manager offers the WebUI of a module which has webroot/, and opens webroot/index.html
+const WEBUI_INDEX: &str = concatcp!(defs::MODULE_WEB_DIR, "/index.html");
+
+// `conflicts=id1,id2` in module.prop
+fn module_conflicts(module_prop: &BTreeMap<String, String>) -> Vec<String> {
+    module_prop
+        .get("conflicts")
+        .map(|v| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(ToOwned::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// a conflict declared by either side counts, the other one may not know about it
+fn conflicts_with(a: &BTreeMap<String, String>, b: &BTreeMap<String, String>) -> bool {
+    let (Some(a_id), Some(b_id)) = (a.get("id"), b.get("id")) else {
+        return false;
+    };
+    module_conflicts(a).contains(b_id) || module_conflicts(b).contains(a_id)
+}
+
+fn check_install_conflicts(module_prop: &BTreeMap<String, String>) -> Result<()> {
+    let id = module_prop["id"].trim();
+    let mut conflicting = BTreeSet::new();
+    let mut check = |path: &Path| {
+        let Ok(installed) = read_module_prop(path) else {
+            return Ok(());
+        };
+        // an update of the same module replaces it, it can't conflict with itself
+        if installed.get("id").map(|v| v.trim()) != Some(id)
+            && conflicts_with(module_prop, &installed)
+        {
+            conflicting.insert(installed["id"].trim().to_owned());
+        }
+        Ok(())
+    };
+    foreach_active_module(&mut check)?;
+    foreach_module(ModuleType::Updated, &mut check)?;
+
+    if conflicting.is_empty() {
+        return Ok(());
+    }
+    let conflicting = conflicting.into_iter().collect::<Vec<_>>().join(", ");
+    bail!(
+        "module {id} conflicts with installed module {conflicting}, disable or uninstall it first, or use --ignore-conflicts to install anyway"
+    )
+}
+
+/// Warn about the enabled modules which conflict with each other, they are still loaded.
+pub fn check_enabled_conflicts() -> Result<()> {
+    let mut enabled = Vec::new();
+    foreach_active_module(|path| {
+        if let Ok(module_prop) = read_module_prop(path) {
+            enabled.push(module_prop);
+        }
+        Ok(())
+    })?;
+
+    for (i, a) in enabled.iter().enumerate() {
+        for b in &enabled[i + 1..] {
+            if conflicts_with(a, b) {
+                warn!(
+                    "modules {} and {} conflict with each other but both are enabled",
+                    a["id"], b["id"]
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn fill_module_prop_defaults(module_prop: &mut BTreeMap<String, String>) {
+    for (field, default) in MODULE_PROP_DEFAULTS {
+        module_prop
+            .entry((*field).to_owned())
+            .or_insert_with(|| (*default).to_owned());
+    }
+}
+
+// a description of a few hundred characters is common, nothing legit comes close to these
+const MAX_MODULE_PROP_LINE: usize = 4096;
+const MAX_MODULE_PROP_SIZE: u64 = 64 * 1024;
+
+// the size and the lines are checked before parsing, so a corrupted or malicious module.prop
+// can't make us read megabytes into memory
+fn parse_module_prop(reader: impl Read) -> Result<BTreeMap<String, String>> {
+    let mut content = Vec::new();
+    reader
+        .take(MAX_MODULE_PROP_SIZE + 1)
+        .read_to_end(&mut content)?;
+    ensure!(
+        content.len() as u64 <= MAX_MODULE_PROP_SIZE,
+        "module.prop is larger than {MAX_MODULE_PROP_SIZE} bytes"
+    );
+    if let Some((i, line)) = content
+        .split(|b| *b == b'\n')
+        .enumerate()
+        .find(|(_, line)| line.len() > MAX_MODULE_PROP_LINE)
+    {
+        bail!(
+            "module.prop:{}: line is {} bytes, longer than {MAX_MODULE_PROP_LINE}",
+            i + 1,
+            line.len()
+        );
+    }
+
+    let mut module_prop = BTreeMap::new();
+    PropertiesIter::new_with_encoding(Cursor::new(content), encoding_rs::UTF_8).read_into(
+        |k, v| {
+            module_prop.insert(k, v);
+        },
+    )?;
+    Ok(module_prop)
+}
+
+fn read_module_prop(module_dir: &Path) -> Result<BTreeMap<String, String>> {
+    parse_module_prop(File::open(module_dir.join("module.prop"))?)
+}
+
+fn is_protected(module_prop: &BTreeMap<String, String>) -> bool {
+    module_prop
+        .get("protected")
+        .is_some_and(|v| v.trim() == "true")
+}
+
+// protected modules are essential for the system, only remove or disable them with force
+fn ensure_not_protected(id: &str, action: &str, force: bool) -> Result<()> {
+    let Ok(module_prop) = read_module_prop(&Path::new(MODULE_DIR).join(id)) else {
+        return Ok(());
+    };
+    if !is_protected(&module_prop) {
+        return Ok(());
+    }
+    ensure!(
+        force,
+        "module {id} is protected, your system may depend on it, use --force to {action} it anyway"
+    );
+    warn!("module {id} is protected, force to {action} it");
+    println!("- Warning: module {id} is protected, your system may depend on it!");
+    Ok(())
+}
+
+pub fn uninstall_module(id: &str, force: bool) -> Result<()> {
+    ensure_not_protected(id, "uninstall", force)?;
+    mark_module_state(id, defs::REMOVE_FILE_NAME, true)?;
+    record_history(id, "uninstall");
+    Ok(())
+}
+
+#[derive(Default)]
+struct UninstallEffects {
+    // stock files hidden by whiteouts or by replaced dirs, visible again
+    revealed: Vec<String>,
+    // stock files the module mounted over, back to the stock ones
+    restored: Vec<String>,
+    // files added by the module, simply gone
+    removed: Vec<String>,
+}
+
+fn is_replaced_dir(path: &Path) -> bool {
+    extattr::lgetxattr(path, defs::REPLACE_DIR_XATTR).is_ok_and(|v| v == b"y")
+}
+
+fn collect_uninstall_effects(
+    module_dir: &Path,
+    target: &Path,
+    effects: &mut UninstallEffects,
+) -> Result<()> {
+    let mut entries: Vec<_> = module_dir.read_dir()?.flatten().collect();
+    entries.sort_by_key(std::fs::DirEntry::file_name);
+    for entry in entries {
+        let target_path = target.join(entry.file_name());
+        let target_str = target_path.display().to_string();
+        let in_stock = target_path.symlink_metadata().is_ok();
+        let metadata = entry.path().symlink_metadata()?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{FileTypeExt, MetadataExt};
+            if metadata.file_type().is_char_device() && metadata.rdev() == 0 {
+                if in_stock {
+                    effects.revealed.push(target_str);
+                }
+                continue;
+            }
+        }
+
+        if !in_stock {
+            effects.removed.push(target_str);
+        } else if metadata.is_dir() {
+            if is_replaced_dir(&entry.path()) {
+                // the stock entries the module doesn't have were hidden by the replaced dir
+                let mut hidden: Vec<String> = target_path
+                    .read_dir()?
+                    .flatten()
+                    .filter(|stock| {
+                        let module_path = entry.path().join(stock.file_name());
+                        module_path.symlink_metadata().is_err()
+                    })
+                    .map(|stock| stock.path().display().to_string())
+                    .collect();
+                hidden.sort();
+                effects.revealed.extend(hidden);
+            }
+            collect_uninstall_effects(&entry.path(), &target_path, effects)?;
+        } else {
+            effects.restored.push(target_str);
+        }
+    }
+    Ok(())
+}
+
+pub fn uninstall_dry_run(id: &str, json: bool) -> Result<()> {
+    let module_dir = Path::new(MODULE_DIR).join(id);
+    ensure!(module_dir.is_dir(), "module {id} not found");
+    let protected = read_module_prop(&module_dir).is_ok_and(|prop| is_protected(&prop));
+
+    // we are in our own mount namespace, compare against the stock partitions
+    crate::init_event::unmount_modules_in_private_ns()?;
+    let mut effects = UninstallEffects::default();
+    let module_system = module_dir.join("system");
+    if module_system.is_dir() {
+        collect_uninstall_effects(&module_system, Path::new("/system"), &mut effects)?;
+    }
+
+    if json {
+        let result = serde_json::json!({
+            "id": id,
+            "protected": protected,
+            "revealed": effects.revealed,
+            "restored": effects.restored,
+            "removed": effects.removed,
+        });
+        return print_json(result, true);
+    }
+
+    println!("- Module {id} would be removed after reboot");
+    if protected {
+        println!("- It is protected, --force is needed to uninstall it");
+    }
+    for path in &effects.revealed {
+        println!("  reveal {path}");
+    }
+    for path in &effects.restored {
+        println!("  restore {path}");
+    }
+    for path in &effects.removed {
+        println!("  remove {path}");
+    }
+    Ok(())
+}
+
+pub fn run_action(id: &str, dump_env: bool) -> Result<()> {
+    let action_script_path = format!("/data/adb/modules/{}/action.sh", id);
+    if dump_env {
+        dump_script_env(Path::new(&action_script_path));
+        return Ok(());
+    }
+    exec_script(&action_script_path, true)
+}
+
+// print what exec_script would run the script with
+fn dump_script_env(path: &Path) {
+    let mut env: BTreeMap<String, String> = std::env::vars().collect();
+    env.extend(script_env().into_iter().map(|(k, v)| (k.to_owned(), v)));
+
+    println!("cwd: {}", path.parent().unwrap_or(path).display());
+    println!("argv: {} sh {}", assets::BUSYBOX_PATH, path.display());
+    for (key, value) in env {
+        println!("{key}={value}");
+    }
+}
+
+pub fn enable_module(id: &str) -> Result<()> {
+    mark_module_state(id, defs::DISABLE_FILE_NAME, false)?;
+    record_history(id, "enable");
+    Ok(())
+}
+
+pub fn disable_module(id: &str, force: bool) -> Result<()> {
+    ensure_not_protected(id, "disable", force)?;
+    mark_module_state(id, defs::DISABLE_FILE_NAME, true)?;
+    record_history(id, "disable");
+    Ok(())
+}
+
+/// Skip the `stage` script of module `id` from the next boot on, the rest of the module still works.
+pub fn skip_stage(id: &str, stage: ModuleStage, skip: bool) -> Result<()> {
+    let stage = stage.as_str();
+    ensure!(
+        Path::new(MODULE_DIR).join(id).exists(),
+        "Module {id} not found"
+    );
+    mark_module_state(id, &skip_stage_file_name(stage), skip)?;
+    let action = if skip { "skip" } else { "unskip" };
+    record_history(id, &format!("{action} {stage}"));
+    Ok(())
+}
+
+// the processes which are still in the process group of the service.sh of module `id`,
+// the ones which started a new session or process group of their own can't be found
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn service_processes(id: &str) -> Result<(i32, Vec<i32>)> {
+    let pgid_file = Path::new(defs::MODULE_SERVICE_PGID_DIR).join(id);
+    let record = std::fs::read_to_string(&pgid_file)
+        .with_context(|| format!("service.sh of {id} didn't run in this boot"))?;
+    let (pgid, started) = record
+        .trim()
+        .split_once(' ')
+        .with_context(|| format!("Invalid {}", pgid_file.display()))?;
+    let (pgid, started): (i32, u64) = (pgid.parse()?, started.parse()?);
+    let group: Vec<procfs::process::Stat> = procfs::process::all_processes()?
+        .flatten()
+        .filter_map(|process| process.stat().ok())
+        .filter(|stat| stat.pgrp == pgid)
+        .collect();
+    ensure!(!group.is_empty(), "service of {id} is not running anymore");
+    // once the whole group exited its pgid may be taken by another one, whose leader isn't
+    // service.sh or whose processes are older than it
+    let reused = group
+        .iter()
+        .any(|stat| stat.starttime < started || (stat.pid == pgid && stat.starttime != started));
+    ensure!(
+        !reused,
+        "service of {id} is not running anymore, its process group belongs to another program"
+    );
+    let mut pids: Vec<i32> = group.iter().map(|stat| stat.pid).collect();
+    pids.sort_unstable();
+    Ok((pgid, pids))
+}
+
+/// SIGSTOP or SIGCONT the processes started by service.sh of module `id`, for debugging
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn pause_services(id: &str, pause: bool) -> Result<()> {
+    let (pgid, pids) = service_processes(id)?;
+    let (signal, action) = if pause {
+        (libc::SIGSTOP, "Paused")
+    } else {
+        (libc::SIGCONT, "Resumed")
+    };
+    if unsafe { libc::killpg(pgid, signal) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to signal the service of {id}"));
+    }
+    let pids: Vec<String> = pids.iter().map(ToString::to_string).collect();
+    println!("- {action} {id}: {}", pids.join(" "));
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn pause_services(_id: &str, _pause: bool) -> Result<()> {
+    bail!("pausing services is only supported on Android")
+}
+
+pub fn boot_watchdog(threshold: Option<u32>) -> Result<()> {
+    if let Some(threshold) = threshold {
+        std::fs::write(defs::BOOT_WATCHDOG_THRESHOLD_FILE, threshold.to_string())?;
+    }
+    let threshold = crate::init_event::boot_watchdog_threshold();
+    if threshold == 0 {
+        println!("threshold: off");
+    } else {
+        println!("threshold: {threshold}");
+    }
+    println!("failed boots: {}", crate::init_event::boot_attempts());
+    Ok(())
+}
+
+pub fn disable_all_modules() -> Result<()> {
+    mark_all_modules(defs::DISABLE_FILE_NAME)?;
+    record_history("*", "disable");
+    Ok(())
+}
+
+const MAX_HISTORY_ENTRIES: usize = 500;
+
+// the process which asked us to do it, e.g. the shell of the manager
+fn history_actor() -> String {
+    #[cfg(unix)]
+    if let Ok(comm) = std::fs::read_to_string(format!(
+        "/proc/{}/comm",
+        std::os::unix::process::parent_id()
+    )) {
+        return comm.trim().to_string();
+    }
+    "unknown".to_string()
+}
+
+// a failure to record the history must never fail the operation itself
+fn record_history(id: &str, action: &str) {
+    let entry = format!(
+        "{}\t{id}\t{action}\t{}",
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+        history_actor()
+    );
+    let content = std::fs::read_to_string(defs::MODULE_HISTORY_FILE).unwrap_or_default();
+    let mut entries: Vec<&str> = content.lines().collect();
+    entries.push(&entry);
+    let start = entries.len().saturating_sub(MAX_HISTORY_ENTRIES);
+    let content = entries[start..].join("\n") + "\n";
+    if let Err(e) = std::fs::write(defs::MODULE_HISTORY_FILE, content) {
+        warn!("Failed to record module history: {e}");
+    }
+}
+
+pub(crate) fn file_size(path: &Path) -> u64 {
+    path.metadata().map(|metadata| metadata.len()).unwrap_or(0)
+}
+
+/// Drop the dead entries of the state ksud keeps: malformed history lines, the install logs and
+/// file lists of modules which are gone and the dead app profiles. The signer pins are kept on
+/// purpose, they still guard a reinstall of the module.
+pub fn compact_state() -> Result<()> {
+    let history = Path::new(defs::MODULE_HISTORY_FILE);
+    let before = file_size(history);
+    let content = std::fs::read_to_string(history).unwrap_or_default();
+    let lines: Vec<&str> = content.lines().collect();
+    let mut kept: Vec<&str> = lines
+        .iter()
+        .copied()
+        .filter(|line| line.splitn(4, '\t').filter(|f| !f.is_empty()).count() >= 3)
+        .collect();
+    kept.drain(..kept.len().saturating_sub(MAX_HISTORY_ENTRIES));
+    if kept.len() != lines.len() {
+        replace_file_atomically(
+            history,
+            &(kept.join("\n") + "\n"),
+            &history.with_extension("bak"),
+        )
+        .with_context(|| "Failed to rewrite module history")?;
+    }
+    println!(
+        "- module history: {} -> {}, {} dead entries dropped",
+        humansize::format_size(before, humansize::DECIMAL),
+        humansize::format_size(file_size(history), humansize::DECIMAL),
+        lines.len() - kept.len()
+    );
+
+    let state_dir = Path::new(defs::MODULE_INSTALL_STATE_DIR);
+    let before = dir_size(state_dir);
+    let mut removed = 0;
+    for entry in std::fs::read_dir(state_dir).into_iter().flatten().flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // interrupted and superseded installs are recovered by the next install of the module
+        let Some(id) = name
+            .strip_suffix(".log")
+            .or_else(|| name.strip_suffix(".files"))
+            .or_else(|| name.strip_suffix(".sha256"))
+        else {
+            continue;
+        };
+        if Path::new(MODULE_DIR).join(id).exists() || Path::new(MODULE_UPDATE_DIR).join(id).exists()
+        {
+            continue;
+        }
+        remove_file(entry.path())?;
+        removed += 1;
+    }
+    println!(
+        "- install state: {} -> {}, {removed} files of removed modules dropped",
+        humansize::format_size(before, humansize::DECIMAL),
+        humansize::format_size(dir_size(state_dir), humansize::DECIMAL)
+    );
+    crate::profile::compact()
+}
+
+pub fn show_history(json: bool) -> Result<()> {
+    let content = match std::fs::read_to_string(defs::MODULE_HISTORY_FILE) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| "Failed to read module history"),
+    };
+    let mut events = Vec::new();
+    for line in content.lines() {
+        let mut fields = line.splitn(4, '\t');
+        let (Some(time), Some(id), Some(action)) = (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let actor = fields.next().unwrap_or("unknown");
+        if json {
+            events.push(serde_json::json!({
+                "time": time,
+                "id": id,
+                "action": action,
+                "actor": actor,
+            }));
+        } else {
+            println!("{time} {action} {id} (by {actor})");
+        }
+    }
+    if json {
+        return print_json(serde_json::json!({ "events": events }), true);
+    }
+    Ok(())
+}
+
+fn mark_all_modules(flag_file: &str) -> Result<()> {
+    let dir = std::fs::read_dir(MODULE_DIR)?;
     for entry in dir.flatten() {
         let path = entry.path();
         let flag = path.join(flag_file);
@@ -422,14 +2194,51 @@ fn mark_all_modules(flag_file: &str) -> Result<()> {
     Ok(())
 }
 
-fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum ModuleSort {
+    /// module name, case insensitive
+    Name,
+    #[default]
+    Id,
+    /// disk usage of the module, largest first
+    Size,
+}
+
+pub(crate) fn dir_size(dir: &Path) -> u64 {
+    jwalk::WalkDir::new(dir)
+        .parallelism(jwalk::Parallelism::Serial)
+        .skip_hidden(false)
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+// each module with its directory, the id may differ from the directory name
+fn sort_modules(modules: &mut [(PathBuf, BTreeMap<String, String>)], sort: ModuleSort) {
+    // id is unique, use it as the tie breaker so the order never depends on read_dir
+    modules.sort_by(|(_, a), (_, b)| a["id"].cmp(&b["id"]));
+    match sort {
+        ModuleSort::Id => {}
+        ModuleSort::Name => modules.sort_by_cached_key(|(_, m)| {
+            m.get("name")
+                .map(|name| name.to_lowercase())
+                .unwrap_or_default()
+        }),
+        ModuleSort::Size => modules.sort_by_cached_key(|(dir, _)| std::cmp::Reverse(dir_size(dir))),
+    }
+}
+
+fn _list_modules(path: &str, sort: ModuleSort) -> Vec<BTreeMap<String, String>> {
     // first check enabled modules
     let dir = std::fs::read_dir(path);
     let Ok(dir) = dir else {
         return Vec::new();
     };
 
-    let mut modules: Vec<HashMap<String, String>> = Vec::new();
+    let mut modules: Vec<(PathBuf, BTreeMap<String, String>)> = Vec::new();
 
     for entry in dir.flatten() {
         let path = entry.path();
@@ -438,17 +2247,16 @@ fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
         if !module_prop.exists() {
             continue;
         }
-        let content = std::fs::read(&module_prop);
-        let Ok(content) = content else {
-            warn!("Failed to read file: {}", module_prop.display());
-            continue;
+        let mut module_prop_map = match read_module_prop(&path) {
+            Ok(module_prop_map) => module_prop_map,
+            Err(e) => {
+                warn!(
+                    "Failed to parse module.prop: {}: {e}",
+                    module_prop.display()
+                );
+                continue;
+            }
         };
-        let mut module_prop_map: HashMap<String, String> = HashMap::new();
-        let encoding = encoding_rs::UTF_8;
-        let result =
-            PropertiesIter::new_with_encoding(Cursor::new(content), encoding).read_into(|k, v| {
-                module_prop_map.insert(k, v);
-            });
 
         if !module_prop_map.contains_key("id") || module_prop_map["id"].is_empty() {
             if let Some(id) = entry.file_name().to_str() {
@@ -460,31 +2268,462 @@ fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
             }
         }
 
+        fill_module_prop_defaults(&mut module_prop_map);
+
         // Add enabled, update, remove flags
         let enabled = !path.join(defs::DISABLE_FILE_NAME).exists();
         let update = path.join(defs::UPDATE_FILE_NAME).exists();
         let remove = path.join(defs::REMOVE_FILE_NAME).exists();
         let web = path.join(defs::MODULE_WEB_DIR).exists();
         let action = path.join(defs::MODULE_ACTION_SH).exists();
+        let protected = is_protected(&module_prop_map);
+        let skipped = skipped_stages(&path).join(",");
 
         module_prop_map.insert("enabled".to_owned(), enabled.to_string());
         module_prop_map.insert("update".to_owned(), update.to_string());
         module_prop_map.insert("remove".to_owned(), remove.to_string());
         module_prop_map.insert("web".to_owned(), web.to_string());
         module_prop_map.insert("action".to_owned(), action.to_string());
+        module_prop_map.insert("protected".to_owned(), protected.to_string());
+        module_prop_map.insert("skippedStages".to_owned(), skipped);
 
-        if result.is_err() {
-            warn!("Failed to parse module.prop: {}", module_prop.display());
+        modules.push((path, module_prop_map));
+    }
+
+    sort_modules(&mut modules, sort);
+    modules.into_iter().map(|(_, module)| module).collect()
+}
+
+const LOCALIZED_MODULE_PROPS: &[&str] = &["name", "description"];
+
+// zh, zh-CN, pt_BR
+fn is_locale(locale: &str) -> bool {
+    let mut parts = locale.split(['-', '_']);
+    parts.next().is_some_and(|lang| {
+        (2..=3).contains(&lang.len()) && lang.chars().all(|c| c.is_ascii_alphabetic())
+    }) && parts.all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+// `name.zh=...` and `description.zh=...` of module.prop move to "localized": {"zh": {...}},
+// a field a locale doesn't provide falls back to the base one
+fn localize_module(module: BTreeMap<String, String>) -> serde_json::Value {
+    let mut fields = serde_json::Map::new();
+    let mut localized: BTreeMap<String, serde_json::Map<String, serde_json::Value>> =
+        BTreeMap::new();
+    for (key, value) in module {
+        match key.split_once('.') {
+            Some((field, locale))
+                if LOCALIZED_MODULE_PROPS.contains(&field) && is_locale(locale) =>
+            {
+                localized
+                    .entry(locale.to_owned())
+                    .or_default()
+                    .insert(field.to_owned(), value.into());
+            }
+            _ => {
+                fields.insert(key, value.into());
+            }
+        }
+    }
+    for strings in localized.values_mut() {
+        for field in LOCALIZED_MODULE_PROPS {
+            if !strings.contains_key(*field) {
+                let base = fields.get(*field).cloned().unwrap_or_default();
+                strings.insert((*field).to_owned(), base);
+            }
+        }
+    }
+    fields.insert("localized".to_owned(), serde_json::json!(localized));
+    fields.into()
+}
+
+/// `json` prints `{schema, modules}`, without it the modules are a bare array as older
+/// managers expect
+pub fn list_modules(sort: ModuleSort, count: bool, json: bool) -> Result<()> {
+    let modules = _list_modules(defs::MODULE_DIR, sort);
+    if count {
+        let enabled = modules.iter().filter(|m| m["enabled"] == "true").count();
+        println!("total: {}", modules.len());
+        println!("enabled: {enabled}");
+        println!("disabled: {}", modules.len() - enabled);
+        return Ok(());
+    }
+    let modules: Vec<serde_json::Value> = modules.into_iter().map(localize_module).collect();
+    if json {
+        return print_json(serde_json::json!({ "modules": modules }), true);
+    }
+    println!("{}", serde_json::to_string_pretty(&modules)?);
+    Ok(())
+}
+
+pub fn module_info(id: &str, show_install_log: bool) -> Result<()> {
+    let module = _list_modules(defs::MODULE_DIR, ModuleSort::Id)
+        .into_iter()
+        .find(|m| m["id"] == id)
+        .with_context(|| format!("Module {id} not found"))?;
+    if show_install_log {
+        let log = install_log_file(id);
+        let content = std::fs::read_to_string(&log).with_context(|| {
+            format!("No install log of {id}, it was installed before logs were kept")
+        })?;
+        print!("{content}");
+        return Ok(());
+    }
+    for (key, value) in &module {
+        println!("{key}: {value}");
+    }
+    let log = install_log_file(id);
+    if log.exists() {
+        println!("installLog: {}", log.display());
+    }
+    Ok(())
+}
+
+/// List the files module `id` provides to the overlay, marking the ones added or removed since
+/// its install when the install kept a file list.
+pub fn module_files(id: &str, json: bool) -> Result<()> {
+    let module_dir = Path::new(defs::MODULE_DIR).join(id);
+    ensure!(module_dir.is_dir(), "Module {id} not found");
+    let files = overlay_files(&module_dir);
+    let manifest: Option<BTreeSet<String>> = std::fs::read_to_string(install_manifest_file(id))
+        .ok()
+        .map(|content| content.lines().map(ToString::to_string).collect());
+
+    let actual: BTreeSet<&str> = files.iter().map(String::as_str).collect();
+    let (added, removed): (Vec<&str>, Vec<&str>) = match &manifest {
+        Some(manifest) => (
+            files
+                .iter()
+                .map(String::as_str)
+                .filter(|file| !manifest.contains(*file))
+                .collect(),
+            manifest
+                .iter()
+                .map(String::as_str)
+                .filter(|file| !actual.contains(file))
+                .collect(),
+        ),
+        None => (Vec::new(), Vec::new()),
+    };
+
+    if json {
+        return print_json(
+            serde_json::json!({
+                "id": id,
+                "files": files,
+                "manifest": manifest.is_some(),
+                "added": added,
+                "removed": removed,
+            }),
+            true,
+        );
+    }
+
+    for file in &files {
+        let marker = if manifest.as_ref().is_some_and(|m| !m.contains(file)) {
+            "+"
+        } else {
+            " "
+        };
+        println!("{marker} {file}");
+    }
+    for file in &removed {
+        println!("- {file}");
+    }
+    if manifest.is_none() {
+        println!("# no file list of the install, it was installed before they were kept");
+    } else if !added.is_empty() || !removed.is_empty() {
+        println!(
+            "# {} files added and {} removed since the install",
+            added.len(),
+            removed.len()
+        );
+    }
+    Ok(())
+}
+
+/// Sizes, file count and id/version of a module zip, read from its central directory and
+/// module.prop, nothing is extracted.
+pub fn zip_info(zip: &str, json: bool) -> Result<()> {
+    let mut archive =
+        zip::ZipArchive::new(File::open(zip)?).with_context(|| format!("{zip} is not a zip"))?;
+    let (mut files, mut compressed, mut uncompressed) = (0, 0, 0);
+    for i in 0..archive.len() {
+        let entry = archive.by_index_raw(i)?;
+        if entry.is_dir() {
             continue;
         }
-        modules.push(module_prop_map);
+        files += 1;
+        compressed += entry.compressed_size();
+        uncompressed += entry.size();
     }
 
-    modules
+    let module_prop = match archive.by_name("module.prop") {
+        Ok(entry) => parse_module_prop(entry)?,
+        Err(_) => BTreeMap::new(),
+    };
+    let id = module_prop.get("id").map(|v| v.trim());
+    let version = module_prop.get("version").map(|v| v.trim());
+
+    if json {
+        return print_json(
+            serde_json::json!({
+                "id": id,
+                "version": version,
+                "files": files,
+                "compressed_size": compressed,
+                "uncompressed_size": uncompressed,
+            }),
+            true,
+        );
+    }
+    println!("id: {}", id.unwrap_or("<none>"));
+    println!("version: {}", version.unwrap_or("<none>"));
+    println!("files: {files}");
+    println!(
+        "compressed: {}",
+        humansize::format_size(compressed, humansize::DECIMAL)
+    );
+    println!(
+        "uncompressed: {}",
+        humansize::format_size(uncompressed, humansize::DECIMAL)
+    );
+    Ok(())
 }
 
-pub fn list_modules() -> Result<()> {
-    let modules = _list_modules(defs::MODULE_DIR);
-    println!("{}", serde_json::to_string_pretty(&modules)?);
+// versionCode is a number in module.prop, but some updateJson files have it as a string
+fn json_version_code(value: &serde_json::Value) -> Option<i64> {
+    value
+        .as_i64()
+        .or_else(|| value.as_str().and_then(|code| code.trim().parse().ok()))
+}
+
+/// Check the updateJson of module `id` for a newer version. With `preview` the module.prop of
+/// the new zip and the changelog are shown too, only the central directory and module.prop are
+/// read from the zip if the server supports range requests. Nothing is verified, busybox wget
+/// doesn't check TLS certificates and the zip isn't read as a whole; it is only checked once
+/// it is installed.
+pub fn check_update(id: &str, preview: bool, json: bool) -> Result<()> {
+    let module_prop = read_module_prop(&Path::new(MODULE_DIR).join(id))
+        .with_context(|| format!("module {id} not found"))?;
+    let update_json = module_prop
+        .get("updateJson")
+        .map(|url| url.trim())
+        .filter(|url| !url.is_empty())
+        .with_context(|| format!("module {id} has no updateJson"))?;
+    let update: serde_json::Value = serde_json::from_slice(&crate::http::get(update_json)?)
+        .with_context(|| format!("Invalid updateJson {update_json}"))?;
+    let version = update["version"].as_str().unwrap_or_default();
+    let version_code = json_version_code(&update["versionCode"]);
+    let zip_url = update["zipUrl"].as_str().unwrap_or_default();
+    let changelog_url = update["changelog"].as_str().unwrap_or_default();
+    let installed_version = module_prop.get("version").map_or("", |v| v.trim());
+    let installed_code = module_prop
+        .get("versionCode")
+        .and_then(|code| code.trim().parse::<i64>().ok());
+    let available = version_code
+        .zip(installed_code)
+        .is_some_and(|(new, old)| new > old);
+
+    let (mut new_prop, mut changelog, mut fetched) = (None, None, None);
+    if preview {
+        ensure!(!zip_url.is_empty(), "updateJson of {id} has no zipUrl");
+        let mut archive = zip::ZipArchive::new(crate::http::RemoteFile::open(zip_url)?)
+            .with_context(|| format!("{zip_url} is not a zip"))?;
+        let prop = parse_module_prop(archive.by_name("module.prop")?)?;
+        let remote = archive.into_inner();
+        new_prop = Some(prop);
+        fetched = Some((remote.fetched(), remote.size()));
+        if !changelog_url.is_empty() {
+            let text = crate::http::get(changelog_url)?;
+            changelog = Some(String::from_utf8_lossy(&text).into_owned());
+        }
+    }
+
+    if json {
+        return print_json(
+            serde_json::json!({
+                "id": id,
+                "version": installed_version,
+                "versionCode": installed_code,
+                "update": {
+                    "version": version,
+                    "versionCode": version_code,
+                    "zipUrl": zip_url,
+                    "changelog": changelog_url,
+                },
+                "available": available,
+                "module_prop": new_prop,
+                "changelog": changelog,
+                // what was fetched may have been changed on the way
+                "verified": false,
+            }),
+            true,
+        );
+    }
+    if available {
+        println!("- {id}: {installed_version} -> {version}");
+    } else {
+        println!("- {id}: {installed_version} is up to date");
+    }
+    if let Some(prop) = &new_prop {
+        println!("- module.prop of {zip_url}:");
+        for (key, value) in prop {
+            println!("  {key}={value}");
+        }
+    }
+    if let Some((fetched, size)) = fetched {
+        println!(
+            "- read {} of the {} zip",
+            humansize::format_size(fetched, humansize::DECIMAL),
+            humansize::format_size(size, humansize::DECIMAL)
+        );
+    }
+    if let Some(changelog) = &changelog {
+        println!("- changelog:");
+        println!("{}", changelog.trim_end());
+    }
+    println!(
+        "- Unverified: the TLS certificate of {} isn't checked, the above may have been changed on the way",
+        if preview { "the servers" } else { "the server" }
+    );
+    Ok(())
+}
+
+/// Check the files of module `id` against the hash manifest its zip shipped. Files missing are
+/// only reported, installer.sh removes some like customize.sh; a modified file is an error.
+pub fn verify_module(id: &str) -> Result<()> {
+    let module_dir = Path::new(MODULE_DIR).join(id);
+    ensure!(module_dir.is_dir(), "Module {id} not found");
+
+    // checked even without a hash manifest, the manager would offer a WebUI which can't load
+    let module_prop = read_module_prop(&module_dir)?;
+    if module_dir.join(defs::MODULE_WEB_DIR).is_dir() && !module_dir.join(WEBUI_INDEX).is_file() {
+        println!(
+            "missing: {WEBUI_INDEX} (the module has {}/)",
+            defs::MODULE_WEB_DIR
+        );
+    }
+
+    let content = std::fs::read_to_string(hash_manifest_file(id))
+        .with_context(|| format!("Module {id} was installed without {HASH_MANIFEST}"))?;
+
+    // the paths are the ones in the zip. If the module has arch trees and one for the device,
+    // common/ and the device arch tree are merged, the files of the arch tree win; other arch
+    // trees are gone
+    let entries = parse_hash_manifest(&content)?;
+    let arch_prefix = format!("{}/", assets::DEVICE_ARCH);
+    let arch_trees = merges_arch_trees(&module_prop)
+        && entries
+            .iter()
+            .any(|(_, path)| path.starts_with(&arch_prefix));
+    let mut expected: BTreeMap<String, String> = BTreeMap::new();
+    let mut merged = Vec::new();
+    for (digest, path) in entries {
+        let (top, rest) = path.split_once('/').unwrap_or((&path, ""));
+        if !arch_trees {
+            if top != "META-INF" {
+                expected.insert(path, digest);
+            }
+        } else if top == COMMON_TREE || top == assets::DEVICE_ARCH {
+            merged.push((top == assets::DEVICE_ARCH, rest.to_string(), digest));
+        } else if !MODULE_ARCHES.contains(&top) && top != "META-INF" {
+            expected.insert(path, digest);
+        }
+    }
+    merged.sort_by_key(|(is_arch, _, _)| *is_arch);
+    for (_, path, digest) in merged {
+        expected.insert(path, digest);
+    }
+
+    let (mut modified, mut missing) = (Vec::new(), Vec::new());
+    for (path, digest) in &expected {
+        let file = module_dir.join(path);
+        if file.symlink_metadata().is_err() {
+            missing.push(path);
+        } else if manifest_digest(&file)?.as_ref() != Some(digest) {
+            modified.push(path);
+        }
+    }
+    for path in &missing {
+        println!("missing: {path}");
+    }
+    for path in &modified {
+        println!("modified: {path}");
+    }
+    ensure!(
+        modified.is_empty(),
+        "{} files of {id} were modified since they were extracted",
+        modified.len()
+    );
+    println!(
+        "- {id}: {} files verified, {} missing",
+        expected.len() - missing.len(),
+        missing.len()
+    );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_prop_is_parsed() {
+        let prop =
+            parse_module_prop(Cursor::new("id=demo\nname=Demo module\n# comment\n")).unwrap();
+        assert_eq!(prop["id"], "demo");
+        assert_eq!(prop["name"], "Demo module");
+    }
+
+    #[test]
+    fn module_prop_line_length_is_capped() {
+        let fits = format!(
+            "id=demo\ndescription={}\n",
+            "a".repeat(MAX_MODULE_PROP_LINE - 12)
+        );
+        assert!(parse_module_prop(Cursor::new(fits)).is_ok());
+
+        let long = format!(
+            "id=demo\ndescription={}\n",
+            "a".repeat(MAX_MODULE_PROP_LINE - 12 + 1)
+        );
+        let err = parse_module_prop(Cursor::new(long))
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("module.prop:2:"), "{err}");
+    }
+
+    #[test]
+    fn module_prop_size_is_capped() {
+        let line = format!("key={}\n", "a".repeat(100));
+        let big = line.repeat(MAX_MODULE_PROP_SIZE as usize / line.len() + 1);
+        let err = parse_module_prop(Cursor::new(big)).unwrap_err().to_string();
+        assert!(err.contains("larger than"), "{err}");
+    }
+
+    #[test]
+    fn update_json_version_code_may_be_a_string() {
+        assert_eq!(json_version_code(&serde_json::json!(42)), Some(42));
+        assert_eq!(json_version_code(&serde_json::json!(" 42 ")), Some(42));
+        assert_eq!(json_version_code(&serde_json::json!("v42")), None);
+        assert_eq!(json_version_code(&serde_json::Value::Null), None);
+    }
+
+    #[test]
+    fn busybox_versions_are_compared_numerically() {
+        assert_eq!(parse_version("1.36.1.git"), [1, 36, 1]);
+        assert_eq!(parse_version("v1.36"), [1, 36]);
+        assert_eq!(parse_version(" V1.36.1 "), [1, 36, 1]);
+        assert!(parse_version("1.36.1") >= parse_version("v1.36"));
+        assert!(parse_version("1.9") < parse_version("1.36"));
+    }
+
+    #[test]
+    fn junk_is_found_at_any_depth() {
+        assert!(is_junk_path("system/.DS_Store"));
+        assert!(is_junk_path("system/etc/.git/config"));
+        assert!(!is_junk_path("system/etc/README.md"));
+        assert!(!is_junk_path("system/etc/git"));
+    }
+}
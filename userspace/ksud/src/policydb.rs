@@ -0,0 +1,399 @@
+// Read the counts `sepolicy stats` reports from a binary policy, e.g. /sys/fs/selinux/policy.
+// It follows policydb_read of the kernel up to the unconditional avtab, everything before the
+// type table is skipped; the rest of the policy is not read.
+use anyhow::{bail, ensure, Context, Result};
+
+const POLICYDB_MAGIC: u32 = 0xf97c_ff8c;
+const POLICYDB_STRING: &[u8] = b"SE Linux";
+
+const POLICYDB_VERSION_VALIDATETRANS: u32 = 19;
+const POLICYDB_VERSION_MLS: u32 = 19;
+const POLICYDB_VERSION_AVTAB: u32 = 20;
+const POLICYDB_VERSION_POLCAP: u32 = 22;
+const POLICYDB_VERSION_PERMISSIVE: u32 = 23;
+const POLICYDB_VERSION_BOUNDARY: u32 = 24;
+const POLICYDB_VERSION_NEW_OBJECT_DEFAULTS: u32 = 27;
+const POLICYDB_VERSION_DEFAULT_TYPE: u32 = 28;
+const POLICYDB_VERSION_CONSTRAINT_NAMES: u32 = 29;
+
+const SYM_COMMONS: usize = 0;
+const SYM_CLASSES: usize = 1;
+const SYM_ROLES: usize = 2;
+const SYM_TYPES: usize = 3;
+const SYM_USERS: usize = 4;
+const SYM_BOOLS: usize = 5;
+const SYM_LEVELS: usize = 6;
+const SYM_CATS: usize = 7;
+
+const TYPEDATUM_PROPERTY_PRIMARY: u32 = 1;
+const TYPEDATUM_PROPERTY_ATTRIBUTE: u32 = 2;
+
+const CEXPR_NAMES: u32 = 5;
+
+const AVTAB_ALLOWED: u16 = 0x0001;
+const AVTAB_XPERMS: u16 = 0x0100 | 0x0200 | 0x0400;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PolicyCounts {
+    pub version: u32,
+    /// primary types, without aliases and attributes
+    pub types: usize,
+    pub attributes: usize,
+    /// allow entries of the unconditional avtab, one per source, target and class
+    pub allow_rules: usize,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .with_context(|| format!("policy is truncated at offset {}", self.pos))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u32s<const N: usize>(&mut self) -> Result<[u32; N]> {
+        let mut values = [0; N];
+        for value in &mut values {
+            *value = self.u32()?;
+        }
+        Ok(values)
+    }
+
+    fn skip(&mut self, len: u32) -> Result<()> {
+        self.take(len as usize).map(|_| ())
+    }
+
+    // mapunit, highbit and count, then count nodes of a u32 startbit and a u64 map
+    fn skip_ebitmap(&mut self) -> Result<()> {
+        let [_, highbit, count] = self.u32s::<3>()?;
+        if highbit == 0 {
+            return Ok(());
+        }
+        for _ in 0..count {
+            self.take(4 + 8)?;
+        }
+        Ok(())
+    }
+
+    fn skip_mls_level(&mut self) -> Result<()> {
+        self.u32()?;
+        self.skip_ebitmap()
+    }
+
+    fn skip_mls_range(&mut self) -> Result<()> {
+        let items = self.u32()?;
+        ensure!(items == 1 || items == 2, "bad mls range of {items} levels");
+        for _ in 0..items {
+            self.u32()?;
+        }
+        for _ in 0..items {
+            self.skip_ebitmap()?;
+        }
+        Ok(())
+    }
+}
+
+fn skip_perm(r: &mut Reader) -> Result<()> {
+    let [len, _value] = r.u32s::<2>()?;
+    r.skip(len)
+}
+
+fn skip_common(r: &mut Reader) -> Result<()> {
+    let [len, _value, _nprim, nel] = r.u32s::<4>()?;
+    r.skip(len)?;
+    for _ in 0..nel {
+        skip_perm(r)?;
+    }
+    Ok(())
+}
+
+fn skip_constraints(r: &mut Reader, version: u32, ncons: u32) -> Result<()> {
+    for _ in 0..ncons {
+        let [_permissions, nexpr] = r.u32s::<2>()?;
+        for _ in 0..nexpr {
+            let [expr_type, _attr, _op] = r.u32s::<3>()?;
+            if expr_type == CEXPR_NAMES {
+                r.skip_ebitmap()?;
+                if version >= POLICYDB_VERSION_CONSTRAINT_NAMES {
+                    // type set: types, negset and flags
+                    r.skip_ebitmap()?;
+                    r.skip_ebitmap()?;
+                    r.u32()?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn skip_class(r: &mut Reader, version: u32) -> Result<()> {
+    let [len, common_len, _value, _nprim, nel, ncons] = r.u32s::<6>()?;
+    r.skip(len)?;
+    r.skip(common_len)?;
+    for _ in 0..nel {
+        skip_perm(r)?;
+    }
+    skip_constraints(r, version, ncons)?;
+    if version >= POLICYDB_VERSION_VALIDATETRANS {
+        let ncons = r.u32()?;
+        skip_constraints(r, version, ncons)?;
+    }
+    if version >= POLICYDB_VERSION_NEW_OBJECT_DEFAULTS {
+        r.u32s::<3>()?;
+    }
+    if version >= POLICYDB_VERSION_DEFAULT_TYPE {
+        r.u32()?;
+    }
+    Ok(())
+}
+
+fn skip_role(r: &mut Reader, version: u32) -> Result<()> {
+    let len = if version >= POLICYDB_VERSION_BOUNDARY {
+        r.u32s::<3>()?[0]
+    } else {
+        r.u32s::<2>()?[0]
+    };
+    r.skip(len)?;
+    // dominates and types
+    r.skip_ebitmap()?;
+    r.skip_ebitmap()
+}
+
+// (is a primary type, is an attribute)
+fn read_type(r: &mut Reader, version: u32) -> Result<(bool, bool)> {
+    let (len, primary, attribute) = if version >= POLICYDB_VERSION_BOUNDARY {
+        let [len, _value, properties, _bounds] = r.u32s::<4>()?;
+        (
+            len,
+            properties & TYPEDATUM_PROPERTY_PRIMARY != 0,
+            properties & TYPEDATUM_PROPERTY_ATTRIBUTE != 0,
+        )
+    } else {
+        let [len, _value, primary] = r.u32s::<3>()?;
+        (len, primary != 0, false)
+    };
+    r.skip(len)?;
+    Ok((primary, attribute))
+}
+
+fn skip_user(r: &mut Reader, version: u32) -> Result<()> {
+    let len = if version >= POLICYDB_VERSION_BOUNDARY {
+        r.u32s::<3>()?[0]
+    } else {
+        r.u32s::<2>()?[0]
+    };
+    r.skip(len)?;
+    // roles
+    r.skip_ebitmap()?;
+    if version >= POLICYDB_VERSION_MLS {
+        r.skip_mls_range()?;
+        r.skip_mls_level()?;
+    }
+    Ok(())
+}
+
+fn skip_bool(r: &mut Reader) -> Result<()> {
+    let [_value, _state, len] = r.u32s::<3>()?;
+    r.skip(len)
+}
+
+fn skip_level(r: &mut Reader) -> Result<()> {
+    let [len, _isalias] = r.u32s::<2>()?;
+    r.skip(len)?;
+    r.skip_mls_level()
+}
+
+fn skip_cat(r: &mut Reader) -> Result<()> {
+    let [len, _value, _isalias] = r.u32s::<3>()?;
+    r.skip(len)
+}
+
+pub fn read_counts(data: &[u8]) -> Result<PolicyCounts> {
+    let mut r = Reader { data, pos: 0 };
+    let [magic, len] = r.u32s::<2>()?;
+    ensure!(
+        magic == POLICYDB_MAGIC,
+        "not a binary policy, magic {magic:#x}"
+    );
+    ensure!(
+        r.take(len as usize)? == POLICYDB_STRING,
+        "not a SELinux policy"
+    );
+    let [version, _config, sym_num, _ocon_num] = r.u32s::<4>()?;
+    if version < POLICYDB_VERSION_AVTAB {
+        bail!("policy version {version} is too old");
+    }
+    ensure!(
+        sym_num as usize <= SYM_CATS + 1,
+        "bad symtab count {sym_num}"
+    );
+    if version >= POLICYDB_VERSION_POLCAP {
+        r.skip_ebitmap()?;
+    }
+    if version >= POLICYDB_VERSION_PERMISSIVE {
+        r.skip_ebitmap()?;
+    }
+
+    let mut counts = PolicyCounts {
+        version,
+        ..PolicyCounts::default()
+    };
+    for sym in 0..sym_num as usize {
+        let [_nprim, nel] = r.u32s::<2>()?;
+        for _ in 0..nel {
+            match sym {
+                SYM_COMMONS => skip_common(&mut r)?,
+                SYM_CLASSES => skip_class(&mut r, version)?,
+                SYM_ROLES => skip_role(&mut r, version)?,
+                SYM_TYPES => match read_type(&mut r, version)? {
+                    (_, true) => counts.attributes += 1,
+                    (true, false) => counts.types += 1,
+                    // an alias
+                    (false, false) => {}
+                },
+                SYM_USERS => skip_user(&mut r, version)?,
+                SYM_BOOLS => skip_bool(&mut r)?,
+                SYM_LEVELS => skip_level(&mut r)?,
+                _ => skip_cat(&mut r)?,
+            }
+        }
+    }
+
+    let nel = r.u32()?;
+    for _ in 0..nel {
+        let [_source, _target, _class, specified] = [r.u16()?, r.u16()?, r.u16()?, r.u16()?];
+        if specified & AVTAB_XPERMS != 0 {
+            // specified, driver and 256 bits of perms
+            r.take(1 + 1 + 32)?;
+        } else {
+            r.u32()?;
+        }
+        if specified & AVTAB_ALLOWED != 0 {
+            counts.allow_rules += 1;
+        }
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32s(out: &mut Vec<u8>, values: &[u32]) {
+        for value in values {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn named(out: &mut Vec<u8>, head: &[u32], name: &str) {
+        u32s(out, head);
+        out.extend_from_slice(name.as_bytes());
+    }
+
+    fn empty_ebitmap(out: &mut Vec<u8>) {
+        u32s(out, &[64, 0, 0]);
+    }
+
+    // a version 30 MLS policy with one class, one role, two types, one attribute and an alias,
+    // one user and level, and an avtab of two allow rules, an auditallow and an allowxperm
+    fn policy() -> Vec<u8> {
+        let mut out = Vec::new();
+        named(&mut out, &[POLICYDB_MAGIC, 8], "SE Linux");
+        u32s(&mut out, &[30, 1, 8, 9]);
+        empty_ebitmap(&mut out);
+        empty_ebitmap(&mut out);
+
+        // commons
+        u32s(&mut out, &[0, 0]);
+        // classes: file with a constraint naming a type set, no validatetrans
+        u32s(&mut out, &[1, 1]);
+        named(&mut out, &[4, 0, 1, 1, 1, 1], "file");
+        named(&mut out, &[4, 1], "read");
+        u32s(&mut out, &[1, 1, CEXPR_NAMES, 0, 0]);
+        u32s(&mut out, &[64, 64, 1, 0]);
+        out.extend_from_slice(&1u64.to_le_bytes());
+        empty_ebitmap(&mut out);
+        empty_ebitmap(&mut out);
+        u32s(&mut out, &[0, 0, 0, 0, 0, 0]);
+        // roles
+        u32s(&mut out, &[1, 1]);
+        named(&mut out, &[2, 1, 0], "r1");
+        empty_ebitmap(&mut out);
+        empty_ebitmap(&mut out);
+        // types
+        u32s(&mut out, &[3, 4]);
+        named(&mut out, &[2, 1, TYPEDATUM_PROPERTY_PRIMARY, 0], "t1");
+        named(&mut out, &[2, 2, TYPEDATUM_PROPERTY_PRIMARY, 0], "t2");
+        named(&mut out, &[2, 1, 0, 0], "a1");
+        named(&mut out, &[2, 3, TYPEDATUM_PROPERTY_ATTRIBUTE, 0], "at");
+        // users
+        u32s(&mut out, &[1, 1]);
+        named(&mut out, &[1, 1, 0], "u");
+        empty_ebitmap(&mut out);
+        u32s(&mut out, &[1, 0]);
+        empty_ebitmap(&mut out);
+        u32s(&mut out, &[0]);
+        empty_ebitmap(&mut out);
+        // bools
+        u32s(&mut out, &[0, 0]);
+        // levels
+        u32s(&mut out, &[1, 1]);
+        named(&mut out, &[2, 0], "s0");
+        u32s(&mut out, &[1]);
+        empty_ebitmap(&mut out);
+        // cats
+        u32s(&mut out, &[0, 0]);
+
+        // avtab
+        u32s(&mut out, &[4]);
+        for specified in [AVTAB_ALLOWED, AVTAB_ALLOWED, 0x0002] {
+            for value in [1u16, 2, 1, specified] {
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            u32s(&mut out, &[1]);
+        }
+        for value in [1u16, 2, 1, 0x0100] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out.extend_from_slice(&[1, 0]);
+        out.extend_from_slice(&[0; 32]);
+        out
+    }
+
+    #[test]
+    fn counts_types_and_allow_rules() {
+        assert_eq!(
+            read_counts(&policy()).unwrap(),
+            PolicyCounts {
+                version: 30,
+                types: 2,
+                attributes: 1,
+                allow_rules: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn truncated_policy_fails() {
+        let policy = policy();
+        assert!(read_counts(&policy[..policy.len() - 1]).is_err());
+        assert!(read_counts(b"not a policy").is_err());
+    }
+}
@@ -1,13 +1,46 @@
-use crate::utils::ensure_dir_exists;
-use crate::{defs, sepolicy};
-use anyhow::{Context, Result};
+use crate::utils::{ensure_dir_exists, replace_file_atomically, shell_quote, sync_parent_dir};
+use crate::{defs, ksucalls, sepolicy};
+use anyhow::{bail, ensure, Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::Path;
 
-pub fn set_sepolicy(pkg: String, policy: String) -> Result<()> {
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ExportFormat {
+    Json,
+    /// a shell script of ksud commands which recreates the profiles
+    Sh,
+}
+
+pub fn set_sepolicy(pkg: String, policy: String, dry_run: bool) -> Result<()> {
+    if dry_run {
+        return preview_sepolicy(&pkg, &policy);
+    }
     ensure_dir_exists(defs::PROFILE_SELINUX_DIR)?;
     let policy_file = Path::new(defs::PROFILE_SELINUX_DIR).join(pkg);
     std::fs::write(&policy_file, policy)?;
-    sepolicy::apply_file(&policy_file)?;
+    sepolicy::apply_file(&policy_file, false)?;
+    Ok(())
+}
+
+// the statements which would be added and removed, nothing is saved or applied
+fn preview_sepolicy(pkg: &str, policy: &str) -> Result<()> {
+    sepolicy::check_rule(policy)?;
+    let policy_file = Path::new(defs::PROFILE_SELINUX_DIR).join(pkg);
+    let old = std::fs::read_to_string(policy_file).unwrap_or_default();
+    let old = sepolicy::statements(&old);
+    let new = sepolicy::statements(policy);
+    let mut changed = false;
+    for statement in old.iter().filter(|s| !new.contains(s)) {
+        println!("- {statement}");
+        changed = true;
+    }
+    for statement in new.iter().filter(|s| !old.contains(s)) {
+        println!("+ {statement}");
+        changed = true;
+    }
+    if !changed {
+        println!("selinux policy of {pkg} is unchanged");
+    }
     Ok(())
 }
 
@@ -18,23 +51,116 @@ pub fn get_sepolicy(pkg: String) -> Result<()> {
     Ok(())
 }
 
+const TEMPLATE_EXTENDS: &str = "extends";
+
+type TemplateFields = serde_json::Map<String, serde_json::Value>;
+
+// the template id a json template inherits from with `"extends": "<id>"`
+fn template_parent(template: &TemplateFields) -> Result<Option<String>> {
+    match template.get(TEMPLATE_EXTENDS) {
+        None => Ok(None),
+        Some(serde_json::Value::String(parent)) => Ok(Some(parent.clone())),
+        Some(_) => bail!("{TEMPLATE_EXTENDS} must be a template id"),
+    }
+}
+
+/// The fields of `template` on top of the resolved fields of the templates it extends, and the
+/// ids of the chain, starting with `id`. None if it isn't a json object, it extends nothing then.
+fn resolve_template(id: &str, template: &str) -> Result<Option<(TemplateFields, Vec<String>)>> {
+    let Ok(mut resolved) = serde_json::from_str::<TemplateFields>(template) else {
+        return Ok(None);
+    };
+    let mut chain = vec![id.to_string()];
+    let mut next = template_parent(&resolved)?;
+    while let Some(parent_id) = next {
+        ensure!(
+            !chain.contains(&parent_id),
+            "template {id} extends itself: {} -> {parent_id}",
+            chain.join(" -> ")
+        );
+        let parent_file = Path::new(defs::PROFILE_TEMPLATE_DIR).join(&parent_id);
+        let parent = std::fs::read_to_string(parent_file)
+            .with_context(|| format!("template {parent_id} doesn't exist"))?;
+        let mut fields: TemplateFields = serde_json::from_str(&parent)
+            .with_context(|| format!("template {parent_id} is not a json object"))?;
+        next = template_parent(&fields)?;
+        fields.extend(resolved);
+        resolved = fields;
+        chain.push(parent_id);
+    }
+    resolved.remove(TEMPLATE_EXTENDS);
+    Ok(Some((resolved, chain)))
+}
+
 // ksud doesn't guarteen the correctness of template, it just save
-pub fn set_template(id: String, template: String) -> Result<()> {
+pub fn set_template(id: String, template: String, dry_run: bool) -> Result<()> {
+    // what it extends must exist and not lead back to it
+    resolve_template(&id, &template)?;
+    let template_file = Path::new(defs::PROFILE_TEMPLATE_DIR).join(&id);
+    if dry_run {
+        match std::fs::read_to_string(&template_file) {
+            Ok(old) if old == template => println!("template {id} is unchanged"),
+            Ok(old) => println!("- {old}\n+ {template}"),
+            Err(_) => println!("+ {template}"),
+        }
+        return Ok(());
+    }
     ensure_dir_exists(defs::PROFILE_TEMPLATE_DIR)?;
-    let template_file = Path::new(defs::PROFILE_TEMPLATE_DIR).join(id);
     std::fs::write(template_file, template)?;
     Ok(())
 }
 
-pub fn get_template(id: String) -> Result<()> {
-    let template_file = Path::new(defs::PROFILE_TEMPLATE_DIR).join(id);
+pub fn get_template(id: String, resolved: bool) -> Result<()> {
+    let template_file = Path::new(defs::PROFILE_TEMPLATE_DIR).join(&id);
     let template = std::fs::read_to_string(template_file)?;
-    println!("{template}");
+    if !resolved {
+        println!("{template}");
+        return Ok(());
+    }
+    match resolve_template(&id, &template)? {
+        Some((mut fields, chain)) => {
+            fields.insert("inherits".to_string(), serde_json::json!(chain));
+            crate::utils::print_json(serde_json::Value::Object(fields), true)?;
+        }
+        None => println!("{template}"),
+    }
     Ok(())
 }
 
-pub fn delete_template(id: String) -> Result<()> {
-    let template_file = Path::new(defs::PROFILE_TEMPLATE_DIR).join(id);
+// the templates which extend `id` directly
+fn template_children(id: &str) -> Vec<String> {
+    let Ok(templates) = std::fs::read_dir(defs::PROFILE_TEMPLATE_DIR) else {
+        return Vec::new();
+    };
+    let mut children: Vec<String> = templates
+        .flatten()
+        .filter_map(|entry| {
+            let content = std::fs::read_to_string(entry.path()).ok()?;
+            let fields: TemplateFields = serde_json::from_str(&content).ok()?;
+            let parent = template_parent(&fields).ok()??;
+            (parent == id).then(|| entry.file_name().to_string_lossy().to_string())
+        })
+        .collect();
+    children.sort();
+    children
+}
+
+/// Delete template `id`, refused while other templates extend it unless `force`, they fail to
+/// resolve without it.
+pub fn delete_template(id: String, force: bool) -> Result<()> {
+    let template_file = Path::new(defs::PROFILE_TEMPLATE_DIR).join(&id);
+    let children = template_children(&id);
+    if !children.is_empty() {
+        ensure!(
+            force,
+            "template {id} is extended by {}, pass --force to delete it anyway",
+            children.join(", ")
+        );
+        println!(
+            "- Warning: the templates extending {id} no longer resolve: {}",
+            children.join(", ")
+        );
+    }
     std::fs::remove_file(template_file)?;
     Ok(())
 }
@@ -69,7 +195,7 @@ pub fn apply_sepolies() -> Result<()> {
             continue;
         };
         let sepolicy = sepolicy.path();
-        if sepolicy::apply_file(&sepolicy).is_ok() {
+        if sepolicy::apply_file(&sepolicy, false).is_ok() {
             log::info!("profile sepolicy applied: {:?}", sepolicy);
         } else {
             log::info!("profile sepolicy apply failed: {:?}", sepolicy);
@@ -77,3 +203,218 @@ pub fn apply_sepolies() -> Result<()> {
     }
     Ok(())
 }
+
+fn read_profile_dir(dir: &str) -> Result<BTreeMap<String, String>> {
+    let mut profiles = BTreeMap::new();
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Ok(profiles);
+    };
+    for entry in entries {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(ToString::to_string) else {
+            continue;
+        };
+        let content = std::fs::read_to_string(entry.path())
+            .with_context(|| format!("read {}", entry.path().display()))?;
+        profiles.insert(name, content);
+    }
+    Ok(profiles)
+}
+
+pub fn export(format: ExportFormat) -> Result<()> {
+    let sepolicies = read_profile_dir(defs::PROFILE_SELINUX_DIR)?;
+    let templates = read_profile_dir(defs::PROFILE_TEMPLATE_DIR)?;
+
+    match format {
+        ExportFormat::Json => {
+            let json = serde_json::json!({
+                "sepolicies": sepolicies,
+                "templates": templates,
+            });
+            crate::utils::print_json(json, true)?;
+        }
+        ExportFormat::Sh => {
+            println!("#!/system/bin/sh");
+            println!(
+                "# KernelSU app profiles, exported by ksud {}",
+                defs::VERSION_NAME
+            );
+            for (pkg, policy) in &sepolicies {
+                println!(
+                    "{} profile set-sepolicy {} {}",
+                    defs::DAEMON_PATH,
+                    shell_quote(pkg),
+                    shell_quote(policy)
+                );
+            }
+            for (id, template) in &templates {
+                println!(
+                    "{} profile set-template {} {}",
+                    defs::DAEMON_PATH,
+                    shell_quote(id),
+                    shell_quote(template)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+const PACKAGES_LIST: &str = "/data/system/packages.list";
+const PER_USER_RANGE: u32 = 100_000;
+const FIRST_APPLICATION_UID: u32 = 10_000;
+const LAST_APPLICATION_UID: u32 = 19_999;
+
+// app id -> packages, shared uids have more than one package
+fn read_installed_packages() -> Result<BTreeMap<u32, Vec<String>>> {
+    let content =
+        std::fs::read_to_string(PACKAGES_LIST).with_context(|| format!("read {PACKAGES_LIST}"))?;
+    let mut packages: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(pkg), Some(Ok(uid))) = (fields.next(), fields.next().map(str::parse::<u32>))
+        else {
+            continue;
+        };
+        packages
+            .entry(uid % PER_USER_RANGE)
+            .or_default()
+            .push(pkg.to_string());
+    }
+    Ok(packages)
+}
+
+/// Orphaned grants are revoked with CMD_REVOKE_ROOT, the one profile change the kernel takes
+/// from root rather than the manager. The kernel side has no tests, check it on a device: with
+/// an orphaned grant `su -c ksud profile reconcile` prints "revoked root of uid N" and
+/// `su -c ksud status` shows one root grant less.
+pub fn reconcile(dry_run: bool) -> Result<()> {
+    let allow_list = ksucalls::get_allow_list().context("Failed to get allow list from kernel")?;
+    let packages = read_installed_packages()?;
+    let installed: BTreeSet<&str> = packages.values().flatten().map(String::as_str).collect();
+    let granted: BTreeSet<&str> = allow_list
+        .iter()
+        .filter_map(|uid| packages.get(&(uid % PER_USER_RANGE)))
+        .flatten()
+        .map(String::as_str)
+        .collect();
+
+    // only app uids belong to a package, e.g. shell is granted without one
+    let orphaned_grants: Vec<u32> = allow_list
+        .iter()
+        .copied()
+        .filter(|uid| {
+            let app_id = uid % PER_USER_RANGE;
+            (FIRST_APPLICATION_UID..=LAST_APPLICATION_UID).contains(&app_id)
+                && !packages.contains_key(&app_id)
+        })
+        .collect();
+
+    let profiles = read_profile_dir(defs::PROFILE_SELINUX_DIR)?;
+    let stale_profiles: Vec<&str> = profiles
+        .keys()
+        .map(String::as_str)
+        .filter(|pkg| !installed.contains(pkg))
+        .collect();
+    let missing_grants: Vec<&str> = profiles
+        .keys()
+        .map(String::as_str)
+        .filter(|pkg| installed.contains(pkg) && !granted.contains(pkg))
+        .collect();
+
+    for uid in &orphaned_grants {
+        println!("orphaned grant: uid {uid} has root but no installed package");
+    }
+    for pkg in &stale_profiles {
+        println!("stale profile: {pkg} is not installed");
+    }
+    // granting root is never done behind the user's back, only report these
+    for pkg in &missing_grants {
+        println!("missing grant: {pkg} has a root profile but isn't granted root");
+    }
+    if orphaned_grants.is_empty() && stale_profiles.is_empty() && missing_grants.is_empty() {
+        println!("allow list and profiles are consistent");
+        return Ok(());
+    }
+    if dry_run {
+        return Ok(());
+    }
+
+    for uid in &orphaned_grants {
+        ksucalls::revoke_root(*uid).with_context(|| format!("Failed to revoke root of {uid}"))?;
+        println!("revoked root of uid {uid}");
+    }
+    for pkg in &stale_profiles {
+        std::fs::remove_file(Path::new(defs::PROFILE_SELINUX_DIR).join(pkg))?;
+        println!("removed profile of {pkg}");
+    }
+    Ok(())
+}
+
+// the lines of `policy` without the statements an earlier line already has, comments are kept
+fn dedup_policy_lines(policy: &str) -> (String, usize) {
+    let mut seen = BTreeSet::new();
+    let mut kept = String::new();
+    let mut dropped = 0;
+    for line in policy.lines() {
+        let statement = line.trim();
+        if !statement.is_empty() && !statement.starts_with('#') && !seen.insert(statement) {
+            dropped += 1;
+            continue;
+        }
+        kept.push_str(line);
+        kept.push('\n');
+    }
+    (kept, dropped)
+}
+
+/// Drop the dead entries of the profile store: empty profiles, the profiles of packages which are
+/// not installed anymore and duplicated statements. The old profiles are kept in
+/// [`defs::PROFILE_BACKUP_DIR`], rewritten ones are swapped in atomically.
+pub fn compact() -> Result<()> {
+    let selinux_dir = Path::new(defs::PROFILE_SELINUX_DIR);
+    let backup_dir = Path::new(defs::PROFILE_BACKUP_DIR).join("selinux");
+    let before = crate::module::dir_size(selinux_dir);
+    // without the package list a stale profile can't be told apart, keep them all
+    let installed: Option<BTreeSet<String>> = read_installed_packages()
+        .ok()
+        .map(|packages| packages.into_values().flatten().collect());
+    let mut dropped_profiles = 0;
+    let mut dropped_statements = 0;
+    for (pkg, policy) in read_profile_dir(defs::PROFILE_SELINUX_DIR)? {
+        let path = selinux_dir.join(&pkg);
+        let stale = installed
+            .as_ref()
+            .is_some_and(|installed| !installed.contains(&pkg));
+        // an interrupted rewrite, the profile itself is still in place
+        if pkg.ends_with(".tmp") || stale || sepolicy::statements(&policy).is_empty() {
+            ensure_dir_exists(&backup_dir)?;
+            std::fs::rename(&path, backup_dir.join(&pkg))?;
+            sync_parent_dir(&path)?;
+            dropped_profiles += 1;
+            continue;
+        }
+        let (kept, dropped) = dedup_policy_lines(&policy);
+        if dropped > 0 {
+            ensure_dir_exists(&backup_dir)?;
+            replace_file_atomically(&path, &kept, &backup_dir.join(&pkg))
+                .with_context(|| format!("Failed to rewrite the profile of {pkg}"))?;
+            dropped_statements += dropped;
+        }
+    }
+    println!(
+        "- app profiles: {} -> {}, {dropped_profiles} dead profiles and {dropped_statements} duplicated statements dropped",
+        humansize::format_size(before, humansize::DECIMAL),
+        humansize::format_size(crate::module::dir_size(selinux_dir), humansize::DECIMAL)
+    );
+
+    // the kernel rewrites it whole from its list on every change and prunes uninstalled apps itself
+    println!(
+        "- allow list: {}, kept compact by the kernel",
+        humansize::format_size(
+            crate::module::file_size(Path::new(defs::KERNEL_ALLOWLIST_FILE)),
+            humansize::DECIMAL
+        )
+    );
+    Ok(())
+}
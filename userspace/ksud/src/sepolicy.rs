@@ -11,7 +11,16 @@ use nom::{
     sequence::Tuple,
     IResult, Parser,
 };
-use std::{ffi, path::Path, vec};
+use std::{
+    collections::HashSet,
+    ffi,
+    path::{Path, PathBuf},
+    vec,
+};
+
+use crate::defs;
+
+const SELINUXFS: &str = "/sys/fs/selinux";
 
 type SeObject<'a> = Vec<&'a str>;
 
@@ -23,6 +32,39 @@ fn parse_single_word(input: &str) -> IResult<&str, &str> {
     take_while1(is_sepolicy_char).parse(input)
 }
 
+// "a name with spaces", quotes and control characters can't be escaped inside of it
+fn parse_quoted(input: &str) -> IResult<&str, &str> {
+    let (input, (_, word, _)) = (
+        tag("\""),
+        take_while1(|c: char| c != '"' && !c.is_control()),
+        tag("\""),
+    )
+        .parse(input)?;
+    Ok((input, word))
+}
+
+// object name of a name transition, usually a file name
+fn parse_name(input: &str) -> IResult<&str, &str> {
+    alt((
+        parse_quoted,
+        take_while1(|c: char| is_sepolicy_char(c) || c == '.'),
+    ))
+    .parse(input)
+}
+
+fn parse_path(input: &str) -> IResult<&str, &str> {
+    alt((
+        parse_quoted,
+        take_while1(|c: char| is_sepolicy_char(c) || c == '/' || c == '.'),
+    ))
+    .parse(input)
+}
+
+// u:object_r:type:s0 or with categories, s0:c512,c768
+fn parse_context(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| is_sepolicy_char(c) || c == ':' || c == ',' || c == '.').parse(input)
+}
+
 fn parse_bracket_objs(input: &str) -> IResult<&str, SeObject> {
     let (input, (_, words, _)) = (
         tag("{"),
@@ -175,7 +217,8 @@ impl<'a> SeObjectParser<'a> for NormalPerm<'a> {
             tag("dontaudit"),
         ))(input)?;
 
-        let (input, _) = space0(input)?;
+        // a space is required, `allow` must not match the beginning of `allowxperm`
+        let (input, _) = space1(input)?;
         let (input, source) = parse_seobj(input)?;
         let (input, _) = space0(input)?;
         let (input, target) = parse_seobj(input)?;
@@ -195,7 +238,7 @@ impl<'a> SeObjectParser<'a> for XPerm<'a> {
             tag("dontauditxperm"),
         ))(input)?;
 
-        let (input, _) = space0(input)?;
+        let (input, _) = space1(input)?;
         let (input, source) = parse_seobj(input)?;
         let (input, _) = space0(input)?;
         let (input, target) = parse_seobj(input)?;
@@ -283,7 +326,7 @@ impl<'a> SeObjectParser<'a> for TypeTransition<'a> {
         }
 
         let (input, _) = space1(input)?;
-        let (input, object) = parse_single_word(input)?;
+        let (input, object) = parse_name(input)?;
 
         Ok((
             input,
@@ -317,9 +360,9 @@ impl<'a> SeObjectParser<'a> for GenFsCon<'a> {
         let (input, _) = space1(input)?;
         let (input, fs) = parse_single_word(input)?;
         let (input, _) = space1(input)?;
-        let (input, path) = parse_single_word(input)?;
+        let (input, path) = parse_path(input)?;
         let (input, _) = space1(input)?;
-        let (input, context) = parse_single_word(input)?;
+        let (input, context) = parse_context(input)?;
         Ok((input, GenFsCon::new(fs, path, context)))
     }
 }
@@ -351,20 +394,46 @@ where
 {
     let mut statements = vec![];
 
-    for line in input.split(['\n', ';']) {
+    for line in split_statements(input) {
         let trimmed_line = line.trim();
         if trimmed_line.is_empty() || trimmed_line.starts_with('#') {
             continue;
         }
-        if let Ok((_, statement)) = PolicyStatement::parse(trimmed_line) {
-            statements.push(statement);
-        } else if strict {
-            bail!("Failed to parse policy statement: {}", line)
+        // the whole statement must be consumed, anything left means we misunderstood it
+        match PolicyStatement::parse(trimmed_line) {
+            Ok(("", statement)) => statements.push(statement),
+            _ if strict => bail!("Failed to parse policy statement: {}", line),
+            _ => log::warn!("Skip invalid policy statement: {}", line),
         }
     }
     Ok(statements)
 }
 
+// statements end with a new line or `;`, but not the ones inside of quotes
+fn split_statements(input: &str) -> Vec<&str> {
+    let mut statements = vec![];
+    let mut in_quote = false;
+    let mut start = 0;
+    for (i, c) in input.char_indices() {
+        match c {
+            '"' => in_quote = !in_quote,
+            '\n' => {
+                // quotes never span lines, an unclosed one is rejected by the parser
+                in_quote = false;
+                statements.push(&input[start..i]);
+                start = i + 1;
+            }
+            ';' if !in_quote => {
+                statements.push(&input[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    statements.push(&input[start..]);
+    statements
+}
+
 const SEPOLICY_MAX_LEN: usize = 128;
 
 const CMD_NORMAL_PERM: u32 = 1;
@@ -377,7 +446,7 @@ const CMD_TYPE_TRANSITION: u32 = 7;
 const CMD_TYPE_CHANGE: u32 = 8;
 const CMD_GENFSCON: u32 = 9;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
 enum PolicyObject {
     All, // for "*", stand for all objects, and is NULL in ffi
     One([u8; SEPOLICY_MAX_LEN]),
@@ -388,7 +457,9 @@ enum PolicyObject {
 impl TryFrom<&str> for PolicyObject {
     type Error = anyhow::Error;
     fn try_from(s: &str) -> Result<Self> {
-        anyhow::ensure!(s.len() <= SEPOLICY_MAX_LEN, "policy object too long");
+        // keep room for the NUL terminator the kernel reads up to
+        anyhow::ensure!(s.len() < SEPOLICY_MAX_LEN, "policy object too long");
+        anyhow::ensure!(!s.contains('\0'), "policy object contains NUL");
         if s == "*" {
             return Ok(PolicyObject::All);
         }
@@ -403,7 +474,7 @@ impl TryFrom<&str> for PolicyObject {
 /// allow domain1 domain2:file1 { read write }; would be expand to two atomic statement
 /// allow domain1 domain2:file1 read;allow domain1 domain2:file1 write;
 #[allow(clippy::too_many_arguments)]
-#[derive(Debug, new)]
+#[derive(Debug, new, Clone, PartialEq, Eq, Hash)]
 struct AtomicStatement {
     cmd: u32,
     subcmd: u32,
@@ -676,8 +747,9 @@ fn to_c_ptr(pol: &PolicyObject) -> *const ffi::c_char {
     }
 }
 
-impl From<AtomicStatement> for FfiPolicy {
-    fn from(policy: AtomicStatement) -> FfiPolicy {
+// the pointers borrow the objects of `policy`, it must outlive the kernel call
+impl From<&AtomicStatement> for FfiPolicy {
+    fn from(policy: &AtomicStatement) -> FfiPolicy {
         FfiPolicy {
             cmd: policy.cmd,
             subcmd: policy.subcmd,
@@ -692,16 +764,147 @@ impl From<AtomicStatement> for FfiPolicy {
     }
 }
 
+impl PolicyObject {
+    // the name, None for "*" and an unset object
+    fn name(&self) -> Option<&str> {
+        match self {
+            PolicyObject::One(buf) => {
+                let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+                std::str::from_utf8(&buf[..len]).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+impl AtomicStatement {
+    // the class and permission of the rule against the live policy, types are left to the
+    // kernel since selinuxfs can't tell an attribute from an unknown type
+    fn problem(&self) -> Option<String> {
+        let class = match self.cmd {
+            CMD_NORMAL_PERM | CMD_XPERM | CMD_TYPE_TRANSITION | CMD_TYPE_CHANGE => {
+                self.sepol3.name()?
+            }
+            _ => return None,
+        };
+        let selinuxfs = Path::new(SELINUXFS);
+        if is_known_class(selinuxfs, class) == Some(false) {
+            return Some(format!("unknown class `{class}`"));
+        }
+        let perm = self.sepol4.name().filter(|_| self.cmd == CMD_NORMAL_PERM)?;
+        (is_known_perm(selinuxfs, class, perm) == Some(false))
+            .then(|| format!("unknown permission `{perm}` for class `{class}`"))
+    }
+}
+
+type PreparedRules<'a> = Vec<(&'a PolicyStatement<'a>, Vec<AtomicStatement>)>;
+
+// every statement is expanded before the first rule reaches the kernel, an invalid one fails
+// the whole batch and leaves the live policy untouched instead of applying only a part of it
+fn prepare_rules<'a>(statements: &'a [PolicyStatement<'a>]) -> Result<PreparedRules<'a>> {
+    statements
+        .iter()
+        .map(|statement| {
+            let rules = Vec::<AtomicStatement>::try_from(statement)
+                .map_err(|e| anyhow::anyhow!("invalid rule {:?}: {}", statement, e))?;
+            Ok((statement, rules))
+        })
+        .collect()
+}
+
+// The kernel patches the live policy in place, one rule per call, and can't take a rule back.
+// There is no staged copy of the policy which could be load-tested and swapped in, so in strict
+// mode the classes and permissions of the whole batch are checked against the live policy
+// before the first rule is sent; a statement which fails the check is never applied in part.
+// A rule the kernel still rejects, e.g. for an unknown type, leaves the rules before it applied.
+fn rejected_statements(prepared: &PreparedRules) -> Vec<(usize, String)> {
+    let mut declared = Vec::new();
+    prepared
+        .iter()
+        .enumerate()
+        .filter_map(|(i, (statement, _))| {
+            explain_semantics(statement, &mut declared, Path::new(SELINUXFS), false)
+                .into_iter()
+                .find(|(is_error, _)| *is_error)
+                .map(|(_, problem)| (i, problem))
+        })
+        .collect()
+}
+
+// block the signals which would stop us in the middle of a batch, they are delivered once it
+// is applied. SIGKILL can't be blocked.
 #[cfg(any(target_os = "linux", target_os = "android"))]
-fn apply_one_rule<'a>(statement: &'a PolicyStatement<'a>, strict: bool) -> Result<()> {
-    let policies: Vec<AtomicStatement> = statement.try_into()?;
+struct BlockSignals(libc::sigset_t);
 
-    for policy in policies {
-        if !rustix::process::ksu_set_policy(&FfiPolicy::from(policy)) {
-            log::warn!("apply rule: {:?} failed.", statement);
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl BlockSignals {
+    fn new() -> Self {
+        unsafe {
+            let mut set: libc::sigset_t = std::mem::zeroed();
+            let mut old: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGQUIT] {
+                libc::sigaddset(&mut set, signal);
+            }
+            libc::pthread_sigmask(libc::SIG_BLOCK, &set, &mut old);
+            BlockSignals(old)
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Drop for BlockSignals {
+    fn drop(&mut self) {
+        unsafe {
+            libc::pthread_sigmask(libc::SIG_SETMASK, &self.0, std::ptr::null_mut());
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn apply_rules(prepared: &PreparedRules, strict: bool) -> Result<()> {
+    if strict {
+        if let Some((i, problem)) = rejected_statements(prepared).first() {
+            bail!(
+                "rule {:?}: {problem}, no rule of the batch was applied",
+                prepared[*i].0
+            );
+        }
+    }
+
+    let _blocked = BlockSignals::new();
+    let mut applied = 0;
+    for (statement, rules) in prepared {
+        let mut failed = 0;
+        for rule in rules {
+            // otherwise only the rules of the statement with an unknown class or permission are
+            // skipped, the others are still applied
+            if !strict {
+                if let Some(problem) = rule.problem() {
+                    log::warn!("skip a rule of {:?}: {problem}", statement);
+                    failed += 1;
+                    continue;
+                }
+            }
+            if rustix::process::ksu_set_policy(&FfiPolicy::from(rule)) {
+                continue;
+            }
             if strict {
-                return Err(anyhow::anyhow!("apply rule {:?} failed.", statement));
+                bail!(
+                    "apply rule {:?} failed, the {applied} statements before it stay applied until reboot",
+                    statement
+                );
             }
+            failed += 1;
+        }
+        if failed > 0 {
+            log::warn!(
+                "apply rule: {:?}: {failed} of its {} rules failed.",
+                statement,
+                rules.len()
+            );
+        } else {
+            applied += 1;
         }
     }
 
@@ -709,31 +912,756 @@ fn apply_one_rule<'a>(statement: &'a PolicyStatement<'a>, strict: bool) -> Resul
 }
 
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
-fn apply_one_rule<'a>(_statement: &'a PolicyStatement<'a>, _strict: bool) -> Result<()> {
+fn apply_rules(_prepared: &PreparedRules, _strict: bool) -> Result<()> {
     unimplemented!()
 }
 
-pub fn live_patch(policy: &str) -> Result<()> {
-    let result = parse_sepolicy(policy.trim(), false)?;
-    for statement in result {
+// the last of the equal rules is kept, so a rule still overrides the ones before it, e.g.
+// allow, deny, allow ends allowed; the rest still reach the kernel in the same order
+fn dedup_rules(prepared: &mut PreparedRules) -> usize {
+    let mut seen = HashSet::new();
+    let mut dropped = 0;
+    for (_, rules) in prepared.iter_mut().rev() {
+        let before = rules.len();
+        let mut kept: Vec<AtomicStatement> = rules
+            .drain(..)
+            .rev()
+            .filter(|rule| seen.insert(rule.clone()))
+            .collect();
+        kept.reverse();
+        *rules = kept;
+        dropped += before - rules.len();
+    }
+    dropped
+}
+
+fn patch(policy: &str, dedup: bool) -> Result<()> {
+    let statements = parse_sepolicy(policy.trim(), false)?;
+    for statement in &statements {
         println!("{statement:?}");
-        apply_one_rule(&statement, false)?;
     }
-    Ok(())
+    let mut prepared = prepare_rules(&statements)?;
+    if dedup {
+        println!("- {} duplicate rules collapsed", dedup_rules(&mut prepared));
+    }
+    apply_rules(&prepared, false)
 }
 
-pub fn apply_file<P: AsRef<Path>>(path: P) -> Result<()> {
+pub fn live_patch(policy: &str) -> Result<()> {
+    patch(policy, false)
+}
+
+/// with `dedup` a rule which the file has more than once is applied once
+pub fn apply_file<P: AsRef<Path>>(path: P, dedup: bool) -> Result<()> {
     let input = std::fs::read_to_string(path)?;
-    live_patch(&input)
+    patch(&input, dedup)
 }
 
-pub fn check_rule(policy: &str) -> Result<()> {
+/// Apply the rules of all the files as one batch, a file which can't be read or parsed is skipped.
+/// With `dedup` a rule which several files have, or one of them has twice, is applied once.
+pub fn apply_files(files: &[PathBuf], dedup: bool) -> Result<()> {
+    let inputs: Vec<(&PathBuf, String)> = files
+        .iter()
+        .filter_map(|file| match std::fs::read_to_string(file) {
+            Ok(input) => Some((file, input)),
+            Err(e) => {
+                log::warn!("skip policy {}: {e}", file.display());
+                None
+            }
+        })
+        .collect();
+    let parsed: Vec<(&PathBuf, Vec<PolicyStatement>)> = inputs
+        .iter()
+        .filter_map(|(file, input)| match parse_sepolicy(input.trim(), false) {
+            Ok(statements) => Some((*file, statements)),
+            Err(e) => {
+                log::warn!("skip policy {}: {e}", file.display());
+                None
+            }
+        })
+        .collect();
+
+    let mut prepared = Vec::new();
+    for (file, statements) in &parsed {
+        match prepare_rules(statements) {
+            Ok(rules) => {
+                log::info!("load policy: {}", file.display());
+                prepared.extend(rules);
+            }
+            Err(e) => log::warn!("skip policy {}: {e}", file.display()),
+        }
+    }
+    if dedup {
+        let dropped = dedup_rules(&mut prepared);
+        log::info!("{dropped} duplicate sepolicy rules collapsed");
+    }
+    apply_rules(&prepared, false)
+}
+
+/// apply the rules in the file, fails before the first rule is applied if a statement is invalid
+/// or names an unknown class or permission, and on the first rule the kernel rejects
+pub fn apply_file_strict<P: AsRef<Path>>(path: P) -> Result<()> {
+    let input = std::fs::read_to_string(path)?;
+    let statements = parse_sepolicy(input.trim(), true)?;
+    apply_rules(&prepare_rules(&statements)?, true)
+}
+
+/// what apply_file_strict checks before it applies the first rule, without applying any
+pub fn check_file_strict<P: AsRef<Path>>(path: P) -> Result<()> {
+    let input = std::fs::read_to_string(path)?;
+    let statements = parse_sepolicy(input.trim(), true)?;
+    let prepared = prepare_rules(&statements)?;
+    if let Some((i, problem)) = rejected_statements(&prepared).first() {
+        bail!("rule {:?}: {problem}", prepared[*i].0);
+    }
+    Ok(())
+}
+
+/// number of statements in the policy and the rules they expand to
+fn count_rules(policy: &str) -> (usize, usize) {
+    let statements = parse_sepolicy(policy.trim(), false).unwrap_or_default();
+    let rules = statements
+        .iter()
+        .filter_map(|statement| Vec::<AtomicStatement>::try_from(statement).ok())
+        .map(|rules| rules.len())
+        .sum();
+    (statements.len(), rules)
+}
+
+fn read_selinuxfs(name: &str) -> Option<String> {
+    std::fs::read_to_string(Path::new(SELINUXFS).join(name))
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+// the classes are listed by selinuxfs, the types and allow rules are counted in the binary
+// policy, they are unknown if it can't be read or parsed.
+pub fn stats(json: bool) -> Result<()> {
+    let policy_version = read_selinuxfs("policyvers");
+    let policy = std::fs::read(Path::new(SELINUXFS).join("policy"));
+    let policy_size = policy.as_ref().ok().map(|policy| policy.len() as u64);
+    let counts = match policy
+        .map_err(anyhow::Error::from)
+        .and_then(|policy| crate::policydb::read_counts(&policy))
+    {
+        Ok(counts) => Some(counts),
+        Err(e) => {
+            log::warn!("Failed to read the live policy: {e:#}");
+            None
+        }
+    };
+    let classes = std::fs::read_dir(Path::new(SELINUXFS).join("class"))
+        .ok()
+        .map(|dir| dir.flatten().count());
+
+    let mut sources = Vec::new();
+    for (source, file) in crate::module::sepolicy_rule_files()?
+        .into_iter()
+        .map(|(id, file)| (format!("module {id}"), file))
+        .chain(
+            std::fs::read_dir(defs::PROFILE_SELINUX_DIR)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(|entry| {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    (format!("profile {name}"), entry.path())
+                }),
+        )
+    {
+        let policy = std::fs::read_to_string(&file).unwrap_or_default();
+        let (statements, rules) = count_rules(&policy);
+        sources.push((source, statements, rules));
+    }
+    let added_statements: usize = sources.iter().map(|s| s.1).sum();
+    let added_rules: usize = sources.iter().map(|s| s.2).sum();
+
+    if json {
+        let sources: Vec<_> = sources
+            .iter()
+            .map(|(source, statements, rules)| {
+                serde_json::json!({
+                    "source": source,
+                    "statements": statements,
+                    "rules": rules,
+                })
+            })
+            .collect();
+        let stats = serde_json::json!({
+            "policy_version": policy_version,
+            "policy_size": policy_size,
+            "classes": classes,
+            "types": counts.as_ref().map(|c| c.types),
+            "attributes": counts.as_ref().map(|c| c.attributes),
+            "allow_rules": counts.as_ref().map(|c| c.allow_rules),
+            "added_statements": added_statements,
+            "added_rules": added_rules,
+            "sources": sources,
+        });
+        return crate::utils::print_json(stats, true);
+    }
+
+    let unknown = || "unknown".to_string();
+    println!("policy version: {}", policy_version.unwrap_or_else(unknown));
+    println!(
+        "policy size: {}",
+        policy_size.map_or_else(unknown, |size| humansize::format_size(
+            size,
+            humansize::DECIMAL
+        ))
+    );
+    println!(
+        "classes: {}",
+        classes.map_or_else(unknown, |n| n.to_string())
+    );
+    match &counts {
+        Some(counts) => {
+            println!("types: {}", counts.types);
+            println!("attributes: {}", counts.attributes);
+            println!("allow rules: {}", counts.allow_rules);
+        }
+        None => {
+            println!("types: unknown");
+            println!("attributes: unknown");
+            println!("allow rules: unknown");
+        }
+    }
+    println!("added by modules and profiles: {added_statements} statements, {added_rules} rules");
+    for (source, statements, rules) in &sources {
+        println!("  {source}: {statements} statements, {rules} rules");
+    }
+    Ok(())
+}
+
+/// the statements of `policy` as written, without the blank lines and comments
+pub fn statements(policy: &str) -> Vec<String> {
+    split_statements(policy)
+        .into_iter()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(ToString::to_string)
+        .collect()
+}
+
+fn read_policy_arg(policy: &str) -> Result<String> {
     let path = Path::new(policy);
-    let policy = if path.exists() {
-        std::fs::read_to_string(path)?
+    if path.exists() {
+        Ok(std::fs::read_to_string(path)?)
     } else {
-        policy.to_string()
+        Ok(policy.to_string())
+    }
+}
+
+pub fn check_rule(policy: &str) -> Result<()> {
+    let policy = read_policy_arg(policy)?;
+    let statements = parse_sepolicy(policy.trim(), true)?;
+    prepare_rules(&statements)?;
+    Ok(())
+}
+
+// the arguments of each statement for --explain, the ones ending with `?` are optional
+const STATEMENT_SHAPES: &[(&[&str], &[&str])] = &[
+    (
+        &["allow", "deny", "auditallow", "dontaudit"],
+        &["source", "target", "class", "perm"],
+    ),
+    (
+        &["allowxperm", "auditallowxperm", "dontauditxperm"],
+        &["source", "target", "class", "operation", "xperm_set"],
+    ),
+    (&["permissive", "enforce"], &["type"]),
+    (&["type"], &["name", "attribute?"]),
+    (&["typeattribute", "attradd"], &["type", "attribute"]),
+    (&["attribute"], &["name"]),
+    (
+        &["type_transition", "name_transition"],
+        &["source", "target", "class", "default_type", "object_name?"],
+    ),
+    (
+        &["type_change", "type_member"],
+        &["source", "target", "class", "default_type"],
+    ),
+    (&["genfscon"], &["fs_name", "partial_path", "fs_context"]),
+];
+
+// words of a statement, a { ... } set or a "quoted name" is one word
+fn statement_words(statement: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut rest = statement.trim().trim_end_matches(';').trim();
+    while !rest.is_empty() {
+        let end = match rest.as_bytes()[0] {
+            b'{' => rest.find('}').map_or(rest.len(), |i| i + 1),
+            b'"' => rest[1..].find('"').map_or(rest.len(), |i| i + 2),
+            _ => rest.find(char::is_whitespace).unwrap_or(rest.len()),
+        };
+        words.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    words
+}
+
+// why a statement the parser rejected is invalid, pointing at the word
+fn explain_syntax(statement: &str) -> String {
+    let words = statement_words(statement);
+    let keyword = words.first().copied().unwrap_or_default();
+    let Some((_, shape)) = STATEMENT_SHAPES
+        .iter()
+        .find(|(keywords, _)| keywords.contains(&keyword))
+    else {
+        let known: Vec<&str> = STATEMENT_SHAPES
+            .iter()
+            .flat_map(|(keywords, _)| keywords.iter().copied())
+            .collect();
+        return format!(
+            "unknown statement `{keyword}`, expect one of: {}",
+            known.join(", ")
+        );
     };
-    parse_sepolicy(policy.trim(), true)?;
+    let usage: Vec<String> = shape
+        .iter()
+        .map(|arg| match arg.strip_suffix('?') {
+            Some(arg) => format!("[{arg}]"),
+            None => format!("<{arg}>"),
+        })
+        .collect();
+    let usage = format!("{keyword} {}", usage.join(" "));
+    let args = &words[1..];
+    let required = shape.iter().filter(|arg| !arg.ends_with('?')).count();
+    if args.len() < required {
+        return format!("missing <{}>, usage: {usage}", shape[args.len()]);
+    }
+    if args.len() > shape.len() {
+        return format!(
+            "unexpected `{}` after <{}>, usage: {usage}",
+            args[shape.len()],
+            shape[shape.len() - 1].trim_end_matches('?')
+        );
+    }
+    for (word, arg) in args.iter().zip(shape.iter()) {
+        let arg = arg.trim_end_matches('?');
+        if word.starts_with('{') && !word.ends_with('}') {
+            return format!("unclosed {{ in <{arg}> `{word}`");
+        }
+        if word.starts_with('"') && (word.len() < 2 || !word.ends_with('"')) {
+            return format!("unclosed quote in <{arg}> `{word}`");
+        }
+        let allowed = |c: char| match arg {
+            "partial_path" => is_sepolicy_char(c) || c == '/' || c == '.',
+            "fs_context" => is_sepolicy_char(c) || c == ':' || c == ',' || c == '.',
+            "object_name" => is_sepolicy_char(c) || c == '.',
+            _ => is_sepolicy_char(c) || c == '*',
+        };
+        let content = word.trim_matches(|c| c == '{' || c == '}' || c == '"');
+        if let Some(c) = content.chars().find(|c| !allowed(*c) && !c.is_whitespace()) {
+            return format!("invalid character `{c}` in <{arg}> `{word}`");
+        }
+    }
+    format!("malformed statement, usage: {usage}")
+}
+
+// None if the live policy can't be asked, e.g. selinuxfs is not mounted
+fn is_known_type(selinuxfs: &Path, name: &str) -> Option<bool> {
+    use std::io::Write;
+    let mut context = std::fs::OpenOptions::new()
+        .write(true)
+        .open(selinuxfs.join("context"))
+        .ok()?;
+    match context.write_all(format!("u:object_r:{name}:s0").as_bytes()) {
+        Ok(()) => Some(true),
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => Some(false),
+        Err(_) => None,
+    }
+}
+
+fn is_known_class(selinuxfs: &Path, class: &str) -> Option<bool> {
+    let classes = selinuxfs.join("class");
+    classes.is_dir().then(|| classes.join(class).is_dir())
+}
+
+fn is_known_perm(selinuxfs: &Path, class: &str, perm: &str) -> Option<bool> {
+    let perms = selinuxfs.join("class").join(class).join("perms");
+    perms.is_dir().then(|| perms.join(perm).exists())
+}
+
+// problems of a statement which parsed, against the live policy of `selinuxfs`; (is an error,
+// message).
+// a name which is no type may well be an attribute, selinuxfs can't tell, so it is only a note
+// the types are only looked up with `check_types`, each takes a write to selinuxfs
+fn explain_semantics(
+    statement: &PolicyStatement,
+    declared: &mut Vec<String>,
+    selinuxfs: &Path,
+    check_types: bool,
+) -> Vec<(bool, String)> {
+    let mut types: Vec<&str> = Vec::new();
+    let mut classes: Vec<&str> = Vec::new();
+    let mut class_perms: Vec<(&str, &str)> = Vec::new();
+    match statement {
+        PolicyStatement::NormalPerm(perm) => {
+            types.extend(perm.source.iter().chain(perm.target.iter()));
+            classes.extend(perm.class.iter());
+            for class in &perm.class {
+                class_perms.extend(perm.perm.iter().map(|p| (*class, *p)));
+            }
+        }
+        PolicyStatement::XPerm(perm) => {
+            types.extend(perm.source.iter().chain(perm.target.iter()));
+            classes.extend(perm.class.iter());
+        }
+        PolicyStatement::TypeState(state) => types.extend(state.stype.iter()),
+        PolicyStatement::TypeAttr(attr) => types.extend(attr.stype.iter()),
+        PolicyStatement::Type(ty) => declared.push(ty.name.to_string()),
+        PolicyStatement::Attr(attr) => declared.push(attr.name.to_string()),
+        PolicyStatement::TypeTransition(tr) => {
+            types.extend([tr.source, tr.target, tr.default_type]);
+            classes.push(tr.class);
+        }
+        PolicyStatement::TypeChange(tc) => {
+            types.extend([tc.source, tc.target, tc.default_type]);
+            classes.push(tc.class);
+        }
+        PolicyStatement::GenFsCon(_) => {}
+    }
+
+    let mut problems = Vec::new();
+    for ty in types
+        .into_iter()
+        .filter(|ty| check_types && *ty != "*" && !declared.iter().any(|d| d == ty))
+    {
+        if is_known_type(selinuxfs, ty) == Some(false) {
+            problems.push((
+                false,
+                format!("`{ty}` is no type of the live policy, unless it is an attribute"),
+            ));
+        }
+    }
+    for class in classes.into_iter().filter(|class| *class != "*") {
+        if is_known_class(selinuxfs, class) == Some(false) {
+            problems.push((true, format!("unknown class `{class}`")));
+        }
+    }
+    for (class, perm) in class_perms
+        .into_iter()
+        .filter(|(class, perm)| *class != "*" && *perm != "*")
+    {
+        if is_known_class(selinuxfs, class) == Some(true)
+            && is_known_perm(selinuxfs, class, perm) == Some(false)
+        {
+            problems.push((
+                true,
+                format!("unknown permission `{perm}` for class `{class}`"),
+            ));
+        }
+    }
+    problems
+}
+
+/// Like `check_rule`, but tell why each statement is invalid, and check the types, classes and
+/// permissions against the live policy.
+pub fn explain_rule(policy: &str) -> Result<()> {
+    let policy = read_policy_arg(policy)?;
+    let mut declared = Vec::new();
+    let mut errors = 0;
+    for (i, statement) in statements(&policy).iter().enumerate() {
+        let problems = match PolicyStatement::parse(statement) {
+            Ok(("", parsed)) => {
+                let mut problems =
+                    explain_semantics(&parsed, &mut declared, Path::new(SELINUXFS), true);
+                if let Err(e) = Vec::<AtomicStatement>::try_from(&parsed) {
+                    problems.push((true, e.to_string()));
+                }
+                problems
+            }
+            _ => vec![(true, explain_syntax(statement))],
+        };
+        for (is_error, problem) in problems {
+            let level = if is_error { "error" } else { "note" };
+            errors += usize::from(is_error);
+            println!("statement {}: `{statement}`\n  {level}: {problem}", i + 1);
+        }
+    }
+    if errors > 0 {
+        bail!("{errors} problems found in the policy");
+    }
+    println!("policy is valid");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_one(policy: &str) -> PolicyStatement {
+        let mut statements = parse_sepolicy(policy, true).unwrap();
+        assert_eq!(statements.len(), 1, "{policy}");
+        statements.remove(0)
+    }
+
+    #[test]
+    fn split_on_semicolons_and_new_lines() {
+        assert_eq!(split_statements("a;b\nc"), vec!["a", "b", "c"]);
+        assert_eq!(split_statements("a;"), vec!["a", ""]);
+        // a quoted `;` is part of the name
+        assert_eq!(
+            split_statements("type_transition a b file c \"x;y\";d"),
+            vec!["type_transition a b file c \"x;y\"", "d"]
+        );
+        // an unclosed quote ends with the line
+        assert_eq!(split_statements("\"a;b\nc;d"), vec!["\"a;b", "c", "d"]);
+    }
+
+    #[test]
+    fn empty_input_and_comments() {
+        assert!(parse_sepolicy("", true).unwrap().is_empty());
+        assert!(parse_sepolicy("  \n\n;;\n", true).unwrap().is_empty());
+        let statements =
+            parse_sepolicy("# allow a b c d\nallow a b file read\n  # deny x", true).unwrap();
+        assert_eq!(statements.len(), 1);
+    }
+
+    #[test]
+    fn brace_lists() {
+        let PolicyStatement::NormalPerm(perm) = parse_one("allow { a b } c file { read  write }")
+        else {
+            panic!("not an allow");
+        };
+        assert_eq!(
+            perm,
+            NormalPerm::new(
+                "allow",
+                vec!["a", "b"],
+                vec!["c"],
+                vec!["file"],
+                vec!["read", "write"]
+            )
+        );
+
+        let PolicyStatement::TypeAttr(attr) = parse_one("typeattribute { a b } mlstrustedsubject")
+        else {
+            panic!("not a typeattribute");
+        };
+        assert_eq!(
+            attr,
+            TypeAttr::new(vec!["a", "b"], vec!["mlstrustedsubject"])
+        );
+    }
+
+    #[test]
+    fn wildcards() {
+        let PolicyStatement::NormalPerm(perm) = parse_one("allow * * * *") else {
+            panic!("not an allow");
+        };
+        assert_eq!(
+            perm,
+            NormalPerm::new("allow", vec!["*"], vec!["*"], vec!["*"], vec!["*"])
+        );
+        // there is no `permissive *`
+        assert!(parse_sepolicy("permissive *", true).is_err());
+    }
+
+    #[test]
+    fn trailing_semicolons() {
+        for policy in [
+            "allow a b file read;",
+            "allow a b file read ;; ",
+            "allow a b file read;\n",
+        ] {
+            assert!(matches!(parse_one(policy), PolicyStatement::NormalPerm(_)));
+        }
+    }
+
+    #[test]
+    fn keywords_need_a_space() {
+        assert!(matches!(
+            parse_one("allowxperm a b file ioctl 0x1234"),
+            PolicyStatement::XPerm(_)
+        ));
+        assert!(matches!(
+            parse_one("type_transition a b file c"),
+            PolicyStatement::TypeTransition(_)
+        ));
+        assert!(parse_sepolicy("allowa b file read", true).is_err());
+    }
+
+    #[test]
+    fn quoted_names_and_paths() {
+        let PolicyStatement::TypeTransition(transition) =
+            parse_one("type_transition a b file c \"a name; with spaces\"")
+        else {
+            panic!("not a type_transition");
+        };
+        assert_eq!(transition.object_name, Some("a name; with spaces"));
+
+        let PolicyStatement::GenFsCon(genfscon) =
+            parse_one("genfscon proc \"/a b\" u:object_r:proc:s0:c512,c768")
+        else {
+            panic!("not a genfscon");
+        };
+        assert_eq!(
+            genfscon,
+            GenFsCon::new("proc", "/a b", "u:object_r:proc:s0:c512,c768")
+        );
+    }
+
+    #[test]
+    fn malformed_statements() {
+        for policy in [
+            "allow a b file",
+            "allow a b file read extra",
+            "allow { a b c file read",
+            "allow a b file read } x",
+            "type_transition a b file c \"unclosed",
+            "type_transition a b file c \"\"",
+            "allow a$b c file read",
+            "allow a b file read`id`",
+            "genfscon proc /a",
+            "unknown a b",
+        ] {
+            assert!(parse_sepolicy(policy, true).is_err(), "{policy}");
+            // skipped with a warning when not strict
+            assert!(
+                parse_sepolicy(policy, false).unwrap().is_empty(),
+                "{policy}"
+            );
+        }
+    }
+
+    #[test]
+    fn objects_fit_the_kernel_buffer() {
+        let long = "a".repeat(SEPOLICY_MAX_LEN);
+        assert!(PolicyObject::try_from(long.as_str()).is_err());
+
+        let fits = "a".repeat(SEPOLICY_MAX_LEN - 1);
+        assert!(PolicyObject::try_from(fits.as_str()).is_ok());
+        assert!(PolicyObject::try_from("a\0b").is_err());
+    }
+
+    #[test]
+    fn dedup_keeps_the_last_rule_in_order() {
+        fn rules(policy: &str) -> Vec<AtomicStatement> {
+            let statements = parse_sepolicy(policy, true).unwrap();
+            prepare_rules(&statements)
+                .unwrap()
+                .into_iter()
+                .flat_map(|(_, rules)| rules)
+                .collect()
+        }
+
+        let statements = parse_sepolicy(
+            "allow a b file read; allow c d file write; \
+             allow { a e } b file { read open }; allow c d file write",
+            true,
+        )
+        .unwrap();
+        let mut prepared = prepare_rules(&statements).unwrap();
+        assert_eq!(dedup_rules(&mut prepared), 2);
+
+        // each duplicate is dropped from the earlier statement, the last one stays where it was
+        let kept: Vec<Vec<AtomicStatement>> =
+            prepared.into_iter().map(|(_, rules)| rules).collect();
+        assert_eq!(
+            kept,
+            vec![
+                vec![],
+                vec![],
+                rules("allow a b file { read open }; allow e b file { read open }"),
+                rules("allow c d file write"),
+            ]
+        );
+
+        // the rule applied last wins, as without dedup
+        let statements = parse_sepolicy(
+            "allow a b file read; deny a b file read; allow a b file read",
+            true,
+        )
+        .unwrap();
+        let mut prepared = prepare_rules(&statements).unwrap();
+        assert_eq!(dedup_rules(&mut prepared), 1);
+        let kept: Vec<Vec<AtomicStatement>> =
+            prepared.into_iter().map(|(_, rules)| rules).collect();
+        assert_eq!(
+            kept,
+            vec![
+                vec![],
+                rules("deny a b file read"),
+                rules("allow a b file read")
+            ]
+        );
+    }
+
+    #[test]
+    fn explain_what_the_parser_rejected() {
+        let usage = "usage: allow <source> <target> <class> <perm>";
+        assert_eq!(
+            explain_syntax("allow a b file"),
+            format!("missing <perm>, {usage}")
+        );
+        assert_eq!(
+            explain_syntax("allow a b file read extra"),
+            format!("unexpected `extra` after <perm>, {usage}")
+        );
+        assert_eq!(
+            explain_syntax("type a b c"),
+            "unexpected `c` after <attribute>, usage: type <name> [attribute]"
+        );
+        assert_eq!(
+            explain_syntax("allow {a b} c file {read"),
+            "unclosed { in <perm> `{read`"
+        );
+        assert_eq!(
+            explain_syntax("type_transition a b file c \"unclosed"),
+            "unclosed quote in <object_name> `\"unclosed`"
+        );
+        assert_eq!(
+            explain_syntax("allow a$b c file read"),
+            "invalid character `$` in <source> `a$b`"
+        );
+        assert!(explain_syntax("unknown a b")
+            .starts_with("unknown statement `unknown`, expect one of: allow, deny,"));
+    }
+
+    #[test]
+    fn explain_classes_and_permissions() {
+        let selinuxfs = tempdir::TempDir::new("ksu_selinuxfs").unwrap();
+        std::fs::create_dir_all(selinuxfs.path().join("class/file/perms/read")).unwrap();
+        let explain = |policy: &str| {
+            explain_semantics(&parse_one(policy), &mut Vec::new(), selinuxfs.path(), false)
+        };
+
+        assert!(explain("allow a b file read").is_empty());
+        assert!(explain("allow a b * *").is_empty());
+        assert_eq!(
+            explain("allow a b nosuch read"),
+            vec![(true, "unknown class `nosuch`".to_string())]
+        );
+        assert_eq!(
+            explain("type_transition a b nosuch c"),
+            vec![(true, "unknown class `nosuch`".to_string())]
+        );
+        assert_eq!(
+            explain("allow a b file { read nosuch }"),
+            vec![(
+                true,
+                "unknown permission `nosuch` for class `file`".to_string()
+            )]
+        );
+
+        // without selinuxfs nothing can be told
+        let missing = selinuxfs.path().join("missing");
+        let statement = parse_one("allow a b nosuch nosuch");
+        assert!(explain_semantics(&statement, &mut Vec::new(), &missing, true).is_empty());
+
+        // the types a policy declares are collected
+        let mut declared = Vec::new();
+        explain_semantics(&parse_one("type a"), &mut declared, &missing, true);
+        explain_semantics(&parse_one("attribute b"), &mut declared, &missing, true);
+        assert_eq!(declared, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn policy_objects_give_back_their_name() {
+        assert_eq!(PolicyObject::try_from("file").unwrap().name(), Some("file"));
+        assert_eq!(PolicyObject::try_from("*").unwrap().name(), None);
+        assert_eq!(PolicyObject::None.name(), None);
+    }
+}
@@ -0,0 +1,89 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::{path::Path, thread::sleep, time::Duration};
+
+use crate::{assets, defs, ksucalls, utils};
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+fn get_modules_status() -> Vec<Value> {
+    let Ok(dir) = std::fs::read_dir(defs::MODULE_DIR) else {
+        return Vec::new();
+    };
+    let mut modules: Vec<(String, Value)> = dir
+        .flatten()
+        .filter(|entry| entry.path().join("module.prop").exists())
+        .map(|entry| {
+            let id = entry.file_name().to_string_lossy().to_string();
+            let path = entry.path();
+            let module = json!({
+                "id": id,
+                "enabled": !path.join(defs::DISABLE_FILE_NAME).exists(),
+                "update": path.join(defs::UPDATE_FILE_NAME).exists(),
+                "remove": path.join(defs::REMOVE_FILE_NAME).exists(),
+            });
+            (id, module)
+        })
+        .collect();
+    modules.sort_by(|a, b| a.0.cmp(&b.0));
+    modules.into_iter().map(|(_, module)| module).collect()
+}
+
+// granted and limit are null if the kernel doesn't tell
+fn get_root_grants_status() -> Value {
+    let granted = ksucalls::get_allow_list().map(|uids| uids.len());
+    let limit = ksucalls::get_allow_list_limit();
+    json!({
+        "granted": granted,
+        "limit": limit,
+        "remaining": granted.zip(limit).map(|(n, limit)| limit.saturating_sub(n)),
+    })
+}
+
+fn get_status(safe_mode: bool, busybox_version: Option<&str>) -> Value {
+    json!({
+        "version": defs::VERSION_NAME,
+        "version_code": defs::VERSION_CODE,
+        "kernel_version": ksucalls::get_version(),
+        "busybox_version": busybox_version,
+        "safe_mode": safe_mode,
+        "boot_completed": utils::getprop("sys.boot_completed").as_deref() == Some("1"),
+        "verbose_log": Path::new(defs::KSUD_VERBOSE_LOG_FILE).exists(),
+        "root_grants": get_root_grants_status(),
+        "modules": get_modules_status(),
+    })
+}
+
+/// print the status as json, with `watch` a new line is printed whenever the status changes
+pub fn status(watch: bool) -> Result<()> {
+    // safe mode can't change during a boot, and the version of busybox takes running it,
+    // which is too much for every poll
+    let safe_mode = utils::is_safe_mode();
+    let busybox_version = assets::busybox_version();
+    let busybox_version = busybox_version.as_deref();
+    let mut last = get_status(safe_mode, busybox_version);
+    if !watch {
+        return utils::print_json(last, true);
+    }
+
+    utils::print_json(last.clone(), false)?;
+    loop {
+        sleep(WATCH_INTERVAL);
+        let mut current = get_status(safe_mode, busybox_version);
+        if current == last {
+            continue;
+        }
+        // wait for the changes to settle down so that a burst of them is reported once
+        loop {
+            sleep(WATCH_DEBOUNCE);
+            let settled = get_status(safe_mode, busybox_version);
+            if settled == current {
+                break;
+            }
+            current = settled;
+        }
+        utils::print_json(current.clone(), false)?;
+        last = current;
+    }
+}
@@ -19,7 +19,9 @@ use rustix::{
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
 pub fn grant_root(global_mnt: bool) -> Result<()> {
-    rustix::process::ksu_grant_root()?;
+    crate::ksucalls::retry_transient(|| {
+        rustix::process::ksu_grant_root().map_err(std::io::Error::from)
+    })?;
 
     let mut command = Command::new("sh");
     let command = unsafe {
@@ -34,6 +36,7 @@ pub fn grant_root(global_mnt: bool) -> Result<()> {
     // add /data/adb/ksu/bin to PATH
     #[cfg(any(target_os = "linux", target_os = "android"))]
     add_path_to_env(defs::BINARY_DIR)?;
+    crate::logger::save();
     Err(command.exec().into())
 }
 
@@ -267,15 +270,78 @@ pub fn root_shell() -> Result<()> {
             }
 
             set_identity(uid, gid, &groups);
+            reset_job_control_signals();
 
             Result::Ok(())
         })
     };
 
+    let interactive = args.is_empty();
     command = command.args(args).arg0(arg0);
+    if interactive && shares_foreground_group() {
+        return run_in_foreground(command);
+    }
     Err(command.exec().into())
 }
 
+const JOB_CONTROL_SIGNALS: &[libc::c_int] = &[
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTSTP,
+    libc::SIGTTIN,
+    libc::SIGTTOU,
+    libc::SIGCHLD,
+];
+
+// signals ignored by the caller stay ignored across exec, a shell started with SIGINT ignored
+// can't interrupt its foreground jobs with Ctrl-C
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn reset_job_control_signals() {
+    for signal in JOB_CONTROL_SIGNALS {
+        unsafe {
+            libc::signal(*signal, libc::SIG_DFL);
+        }
+    }
+}
+
+// whether we are in the foreground process group of the terminal without leading it, e.g. su
+// started by a `sh -c` without job control, then Ctrl-C would kill the caller too
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn shares_foreground_group() -> bool {
+    unsafe {
+        libc::isatty(libc::STDIN_FILENO) == 1
+            && libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp()
+            && libc::getpgrp() != libc::getpid()
+    }
+}
+
+// run the shell in a process group of its own which owns the terminal, so Ctrl-C and job
+// control only reach the shell and its jobs, and give the terminal back once it exits
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn run_in_foreground(command: &mut Command) -> Result<()> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let caller_pgrp = unsafe { libc::getpgrp() };
+    let command = unsafe {
+        command.process_group(0).pre_exec(|| {
+            utils::set_foreground_group(libc::getpid());
+            Result::Ok(())
+        })
+    };
+    let mut child = command.spawn()?;
+    // either side may win the race, both set it
+    utils::set_foreground_group(child.id() as libc::pid_t);
+    let status = child.wait();
+    utils::set_foreground_group(caller_pgrp);
+
+    let status = status?;
+    let code = status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1);
+    std::process::exit(code);
+}
+
 fn add_path_to_env(path: &str) -> Result<()> {
     let mut paths =
         env::var_os("PATH").map_or(Vec::new(), |val| env::split_paths(&val).collect::<Vec<_>>());
@@ -3,12 +3,14 @@ use std::{
     fs::{create_dir_all, remove_file, write, File, OpenOptions},
     io::{
         ErrorKind::{AlreadyExists, NotFound},
-        Write,
+        IsTerminal, Write,
     },
     path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
 
-use crate::ksucalls;
+use crate::{defs, ksucalls};
 #[allow(unused_imports)]
 use std::fs::{set_permissions, Permissions};
 #[cfg(unix)]
@@ -30,6 +32,132 @@ pub fn ensure_clean_dir(dir: impl AsRef<Path>) -> Result<()> {
     Ok(std::fs::create_dir_all(path)?)
 }
 
+/// Move the tree `src` to `dst` like rename(2) does. When they are on different filesystems,
+/// e.g. the modules dir is a mounted image, the tree is copied with its owners, modes and
+/// xattrs and then removed. Either way `dst` only appears once it is complete.
+pub fn move_dir(src: &Path, dst: &Path) -> Result<()> {
+    let err = match std::fs::rename(src, dst) {
+        Result::Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if err.raw_os_error() == Some(libc::EXDEV) {
+        let total = jwalk::WalkDir::new(src)
+            .skip_hidden(false)
+            .into_iter()
+            .count();
+        log::info!(
+            "{} and {} are on different filesystems, copying {total} entries, it may take a while",
+            src.display(),
+            dst.display()
+        );
+        let mut tmp = dst.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = std::path::PathBuf::from(tmp);
+        if tmp.exists() {
+            std::fs::remove_dir_all(&tmp)?;
+        }
+        let mut copied = 0;
+        let copy = copy_tree(src, &tmp, &mut || {
+            copied += 1;
+            if copied % 500 == 0 {
+                log::info!("copied {copied}/{total} entries of {}", src.display());
+            }
+        });
+        if let Err(e) = copy {
+            // src is untouched, don't leave half of it behind
+            std::fs::remove_dir_all(&tmp).ok();
+            return Err(e)
+                .with_context(|| format!("Failed to copy {} to {}", src.display(), dst.display()));
+        }
+        std::fs::rename(&tmp, dst)?;
+        std::fs::remove_dir_all(src)?;
+        log::info!("moved {} to {} by copying", src.display(), dst.display());
+        return Ok(());
+    }
+    Err(Error::from(err))
+        .with_context(|| format!("Failed to move {} to {}", src.display(), dst.display()))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn copy_tree(src: &Path, dst: &Path, progress: &mut impl FnMut()) -> Result<()> {
+    copy_entry(src, dst)?;
+    progress();
+    for entry in std::fs::read_dir(src)?.flatten() {
+        let path = entry.path();
+        let target = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_tree(&path, &target, progress)?;
+        } else {
+            copy_entry(&path, &target)?;
+            progress();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn copy_entry(src: &Path, dst: &Path) -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = std::fs::symlink_metadata(src)?;
+    let file_type = metadata.file_type();
+    if file_type.is_symlink() {
+        std::os::unix::fs::symlink(std::fs::read_link(src)?, dst)?;
+    } else if file_type.is_dir() {
+        std::fs::create_dir(dst)?;
+    } else if file_type.is_file() {
+        std::fs::copy(src, dst)?;
+    } else {
+        // device nodes, such as the whiteouts of overlayfs
+        let path = std::ffi::CString::new(dst.as_os_str().as_bytes())?;
+        let ret = unsafe {
+            libc::mknod(
+                path.as_ptr(),
+                metadata.mode() as libc::mode_t,
+                metadata.rdev() as libc::dev_t,
+            )
+        };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    std::os::unix::fs::lchown(dst, Some(metadata.uid()), Some(metadata.gid()))?;
+    // chown clears setuid and setgid, so the mode comes after it; symlinks have no mode
+    if !file_type.is_symlink() {
+        set_permissions(dst, metadata.permissions())?;
+    }
+
+    // SELinux contexts and the opaque dirs of overlayfs
+    for name in extattr::llistxattr(src).unwrap_or_default() {
+        if let Result::Ok(value) = extattr::lgetxattr(src, &name) {
+            extattr::lsetxattr(dst, &name, value, extattr::Flags::empty())
+                .with_context(|| format!("Failed to copy xattr {name:?} of {}", src.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// `value` with the "schema" field if it is an object, arrays can't carry it
+pub fn with_schema(mut value: serde_json::Value) -> serde_json::Value {
+    if let Some(object) = value.as_object_mut() {
+        object.insert("schema".to_owned(), defs::JSON_SCHEMA_VERSION.into());
+    }
+    value
+}
+
+/// print the json output of a command, see `defs::JSON_SCHEMA_VERSION`
+pub fn print_json(value: serde_json::Value, pretty: bool) -> Result<()> {
+    let value = with_schema(value);
+    if pretty {
+        println!("{}", serde_json::to_string_pretty(&value)?);
+    } else {
+        println!("{}", serde_json::to_string(&value)?);
+    }
+    Ok(())
+}
+
 pub fn ensure_file_exists<T: AsRef<Path>>(file: T) -> Result<()> {
     match File::options().write(true).create_new(true).open(&file) {
         Result::Ok(_) => Ok(()),
@@ -55,6 +183,41 @@ pub fn ensure_dir_exists<T: AsRef<Path>>(dir: T) -> Result<()> {
     }
 }
 
+// a rename(2) or unlink(2) is only durable once the directory holding the entry is synced
+pub fn sync_parent_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("Failed to sync {}", dir.display()))
+}
+
+/// Replace `path` with `content`, the old file is kept as `backup`. Both are written to a temporary
+/// file first and swapped in by rename(2), so an interrupted rewrite leaves the old file in place.
+pub fn replace_file_atomically(path: &Path, content: &str, backup: &Path) -> Result<()> {
+    // appended, with_extension would replace the last part of a package name
+    let tmp_of = |path: &Path| {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::path::PathBuf::from(tmp)
+    };
+    if path.exists() {
+        let tmp = tmp_of(backup);
+        std::fs::copy(path, &tmp)?;
+        File::open(&tmp)?.sync_all()?;
+        std::fs::rename(&tmp, backup)?;
+        sync_parent_dir(backup)?;
+    }
+    let tmp = tmp_of(path);
+    let mut file = File::create(&tmp)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    sync_parent_dir(path)
+}
+
 pub fn ensure_binary<T: AsRef<Path>>(
     path: T,
     contents: &[u8],
@@ -110,6 +273,13 @@ pub fn is_safe_mode() -> bool {
     safemode
 }
 
+/// whether the credential encrypted storage of user 0 is available,
+/// it is always available on devices without file based encryption.
+pub fn is_ce_storage_available() -> bool {
+    getprop("ro.crypto.type").as_deref() != Some("file")
+        || getprop("sys.user.0.ce_available").as_deref() == Some("true")
+}
+
 pub fn get_zip_uncompressed_size(zip_path: &str) -> Result<u64> {
     let mut zip = zip::ZipArchive::new(std::fs::File::open(zip_path)?)?;
     let total: u64 = (0..zip.len())
@@ -178,4 +348,134 @@ pub fn umask(_mask: u32) {
 
 pub fn has_magisk() -> bool {
     which::which("magisk").is_ok()
-}
\ No newline at end of file
+}
+
+/// quote a string for POSIX shell, so that it is always passed as a single word
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+static NO_COLOR: AtomicBool = AtomicBool::new(false);
+
+/// for --no-color, NO_COLOR and a stdout which isn't a terminal disable it too
+pub fn disable_color() {
+    NO_COLOR.store(true, Ordering::Relaxed);
+}
+
+// https://no-color.org, any non-empty NO_COLOR disables it
+pub fn color_enabled() -> bool {
+    !NO_COLOR.load(Ordering::Relaxed)
+        && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+        && std::io::stdout().is_terminal()
+}
+
+/// `s` in red if color is enabled, e.g. for errors in the human output
+pub fn red(s: &str) -> String {
+    if color_enabled() {
+        format!("\x1b[31m{s}\x1b[0m")
+    } else {
+        s.to_owned()
+    }
+}
+
+/// exit code of a command killed by --command-timeout, the same as timeout(1)
+pub const TIMED_OUT_EXIT_CODE: i32 = 124;
+const COMMAND_TIMEOUT_CHILD_ENV: &str = "KSUD_COMMAND_TIMEOUT_CHILD";
+const COMMAND_TIMEOUT_GRACE: Duration = Duration::from_secs(1);
+
+/// whether we are the ksud doing the work for a parent which enforces --command-timeout; the
+/// variable is cleared, the module scripts we run must not inherit it
+pub fn is_timeout_child() -> bool {
+    let child = std::env::var_os(COMMAND_TIMEOUT_CHILD_ENV).is_some();
+    std::env::remove_var(COMMAND_TIMEOUT_CHILD_ENV);
+    child
+}
+
+// a background process group gets SIGTTOU when it changes the foreground one
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn set_foreground_group(pgrp: libc::pid_t) {
+    unsafe {
+        let ttou = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+        libc::tcsetpgrp(libc::STDIN_FILENO, pgrp);
+        libc::signal(libc::SIGTTOU, ttou);
+    }
+}
+
+/// Run this ksud command again in a child and wait for it until `timeout`, returns the exit code
+/// of the child or TIMED_OUT_EXIT_CODE. On timeout its process group, with the scripts it started,
+/// gets SIGTERM and SIGKILL a moment later.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn run_with_timeout(timeout: Duration) -> Result<i32> {
+    use std::os::unix::process::CommandExt;
+
+    // a background process group is stopped by SIGTTIN when it reads the terminal, e.g. for the
+    // reboot prompt, so the child gets the terminal if we have it and we take it back after
+    let caller_pgrp = unsafe { libc::getpgrp() };
+    let foreground = unsafe {
+        libc::isatty(libc::STDIN_FILENO) == 1 && libc::tcgetpgrp(libc::STDIN_FILENO) == caller_pgrp
+    };
+
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(COMMAND_TIMEOUT_CHILD_ENV, "1")
+        .process_group(0);
+    // it is out of our process group, don't leave it running when we are killed
+    unsafe {
+        command.pre_exec(move || {
+            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+            if foreground {
+                set_foreground_group(libc::getpid());
+            }
+            std::io::Result::Ok(())
+        });
+    }
+    let mut child = command
+        .spawn()
+        .with_context(|| "Failed to run the command")?;
+    let pgid = child.id() as i32;
+    if foreground {
+        // either side may win the race, both set it
+        set_foreground_group(pgid);
+    }
+
+    let result = wait_with_timeout(&mut child, pgid, timeout);
+    if foreground {
+        set_foreground_group(caller_pgrp);
+    }
+    result
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn wait_with_timeout(
+    child: &mut std::process::Child,
+    pgid: libc::pid_t,
+    timeout: Duration,
+) -> Result<i32> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status
+                .code()
+                .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    unsafe { libc::killpg(pgid, libc::SIGTERM) };
+    let grace = Instant::now() + COMMAND_TIMEOUT_GRACE;
+    while child.try_wait()?.is_none() && Instant::now() < grace {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    unsafe { libc::killpg(pgid, libc::SIGKILL) };
+    let _ = child.wait();
+    eprintln!("ksud: timed out after {}s", timeout.as_secs());
+    Ok(TIMED_OUT_EXIT_CODE)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn run_with_timeout(_timeout: Duration) -> Result<i32> {
+    bail!("--command-timeout is only supported on Android")
+}
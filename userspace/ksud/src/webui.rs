@@ -0,0 +1,236 @@
+use anyhow::{ensure, Context, Result};
+use log::{info, warn};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::defs;
+
+const MAX_HEADER_LEN: usize = 8192;
+const TOKEN_HEADER: &str = "x-ksu-token";
+const BRIDGE_PATH: &str = "/ksu/exec";
+
+// the ksud commands a WebUI may run through the bridge, `module action` only for its own module
+const BRIDGE_COMMANDS: &[&[&str]] = &[&["module", "list"], &["module", "history"], &["status"]];
+
+static STOP: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_signal(_: libc::c_int) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+fn random_token() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                if let Ok(b) = u8::from_str_radix(hex, 16) {
+                    out.push(b);
+                    i += 3;
+                    continue;
+                }
+                out.push(b'%');
+            }
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()).unwrap_or("") {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff2" => "font/woff2",
+        "txt" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    Ok(())
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    token: Option<String>,
+}
+
+fn read_request(stream: &TcpStream) -> Result<Request> {
+    let mut reader = BufReader::new(stream.take(MAX_HEADER_LEN as u64));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let target = parts.next().unwrap_or("/");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let mut token = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case(TOKEN_HEADER) {
+                token = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    Ok(Request {
+        method,
+        path: percent_decode(path),
+        query: query.to_string(),
+        token,
+    })
+}
+
+// a path inside webroot, never outside of it even through `..` or symlinks
+fn resolve_file(webroot: &Path, path: &str) -> Option<PathBuf> {
+    let relative = path.trim_start_matches('/');
+    let mut file = webroot.join(relative);
+    if file.is_dir() {
+        file = file.join("index.html");
+    }
+    let file = file.canonicalize().ok()?;
+    (file.starts_with(webroot) && file.is_file()).then_some(file)
+}
+
+fn run_bridge(id: &str, query: &str) -> Result<serde_json::Value> {
+    let cmd = query
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .find(|(k, _)| *k == "cmd")
+        .map(|(_, v)| percent_decode(v))
+        .unwrap_or_default();
+    let args: Vec<&str> = cmd.split_whitespace().collect();
+    let own_action = args == ["module", "action", id];
+    ensure!(
+        own_action || BRIDGE_COMMANDS.contains(&args.as_slice()),
+        "command is not allowed: {cmd}"
+    );
+
+    let output = Command::new(std::env::current_exe()?)
+        .args(&args)
+        .output()?;
+    Ok(crate::utils::with_schema(serde_json::json!({
+        "errno": output.status.code().unwrap_or(-1),
+        "stdout": String::from_utf8_lossy(&output.stdout),
+        "stderr": String::from_utf8_lossy(&output.stderr),
+    })))
+}
+
+fn handle(mut stream: TcpStream, id: &str, webroot: &Path, token: &str) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let request = read_request(&stream)?;
+    info!("webui {id}: {} {}", request.method, request.path);
+
+    if request.path == BRIDGE_PATH {
+        if request.token.as_deref() != Some(token) {
+            return respond(&mut stream, "403 Forbidden", "text/plain", b"bad token");
+        }
+        return match run_bridge(id, &request.query) {
+            Ok(result) => respond(
+                &mut stream,
+                "200 OK",
+                "application/json",
+                result.to_string().as_bytes(),
+            ),
+            Err(e) => respond(
+                &mut stream,
+                "403 Forbidden",
+                "text/plain",
+                e.to_string().as_bytes(),
+            ),
+        };
+    }
+
+    if request.method != "GET" && request.method != "HEAD" {
+        return respond(&mut stream, "405 Method Not Allowed", "text/plain", b"");
+    }
+    let Some(file) = resolve_file(webroot, &request.path) else {
+        return respond(&mut stream, "404 Not Found", "text/plain", b"not found");
+    };
+    let body = if request.method == "HEAD" {
+        Vec::new()
+    } else {
+        std::fs::read(&file)?
+    };
+    respond(&mut stream, "200 OK", content_type(&file), &body)
+}
+
+/// Serve the webroot of module `id` read-only on localhost, until SIGINT or SIGTERM.
+/// `/ksu/exec?cmd=...` runs an allowed ksud command, it needs the token printed at startup.
+pub fn serve(id: &str, port: u16) -> Result<()> {
+    let webroot = Path::new(defs::MODULE_DIR)
+        .join(id)
+        .join(defs::MODULE_WEB_DIR)
+        .canonicalize()
+        .with_context(|| format!("module {id} has no {}", defs::MODULE_WEB_DIR))?;
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+        .with_context(|| format!("Failed to listen on port {port}"))?;
+    listener.set_nonblocking(true)?;
+    let token = random_token()?;
+
+    #[cfg(unix)]
+    {
+        let handler: extern "C" fn(libc::c_int) = on_signal;
+        unsafe {
+            libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+            libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+        }
+    }
+
+    println!("- Serving {id} on http://{}", listener.local_addr()?);
+    println!("- Token: {token}");
+
+    while !STOP.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                if let Err(e) = handle(stream, id, &webroot, &token) {
+                    warn!("webui {id}: {e}");
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    println!("- Stopped serving {id}");
+    Ok(())
+}